@@ -0,0 +1,79 @@
+//! Measures how quickly records can be added to a topic under different
+//! write settings. Run with `cargo bench --bench append`.
+
+use listdb_engine::config::{Durability, EngineConfig};
+use listdb_engine::dbprocess::DBResponse;
+use listdb_engine::DBEngine;
+use std::fs;
+use std::path::MAIN_SEPARATOR;
+use std::time::{Duration, Instant};
+
+const RECORDS: usize = 20_000;
+const SYNCED_RECORDS: usize = 500;
+
+fn main() {
+    let write_through = EngineConfig {
+        snapshot_interval: None,
+        ..EngineConfig::default()
+    };
+    let buffered = EngineConfig {
+        write_buffer: 64 * 1024,
+        ..write_through.clone()
+    };
+    let every_write = EngineConfig {
+        durability: Durability::EveryWrite,
+        ..write_through.clone()
+    };
+    let group_commit = EngineConfig {
+        durability: Durability::Interval(Duration::from_millis(10)),
+        ..buffered.clone()
+    };
+
+    let write_through_rate = run("write through", write_through, RECORDS);
+    let buffered_rate = run("buffered 64 KiB", buffered, RECORDS);
+    println!(
+        "buffering: {:.1}x the throughput of writing through",
+        buffered_rate / write_through_rate
+    );
+    let every_write_rate = run("sync every write", every_write, SYNCED_RECORDS);
+    let group_commit_rate = run("group commit 10 ms", group_commit, RECORDS);
+    println!(
+        "group commit: {:.1}x the throughput of syncing every write",
+        group_commit_rate / every_write_rate
+    );
+}
+
+/// Adds `records` records to a new topic and returns the records added per
+/// second, including the final flush when the topic is closed.
+fn run(name: &str, config: EngineConfig, records: usize) -> f64 {
+    let directory = std::env::temp_dir().join(format!("listdb-bench-{}", std::process::id()));
+    fs::create_dir_all(&directory).expect("unable to create the database directory");
+    let home = format!("{}{}", directory.display(), MAIN_SEPARATOR);
+    let mut engine = DBEngine::with_config(&home, config);
+    expect_ok(engine.request("CREATE TOPIC bench"));
+    expect_ok(engine.request("OPEN TOPIC bench"));
+    let start = Instant::now();
+    for index in 0..records {
+        expect_ok(engine.request(&format!("ADD benchmark record number {}", index)));
+    }
+    expect_ok(engine.request("CLOSE"));
+    let elapsed = start.elapsed();
+    let rate = records as f64 / elapsed.as_secs_f64();
+    println!(
+        "{:<20} {:>7} records in {:>8.1} ms  {:>10.0} records/s",
+        name,
+        records,
+        elapsed.as_secs_f64() * 1000.0,
+        rate
+    );
+    fs::remove_dir_all(&directory).expect("unable to remove the database directory");
+    rate
+}
+
+fn expect_ok(response: DBResponse<String>) {
+    match response {
+        DBResponse::Error(message) | DBResponse::Invalid(message) => panic!("{}", message),
+        DBResponse::Unknown(command) => panic!("unknown command {}", command),
+        _ => (),
+    }
+}
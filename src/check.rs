@@ -0,0 +1,111 @@
+//! Checking a whole database: every topic in every directory, and any file
+//! in the database that belongs to neither.
+
+use crate::config::EngineConfig;
+use crate::directories::DirectoryContext;
+use std::path::PathBuf;
+
+/// Result of checking a database.
+pub struct DatabaseReport {
+  /// Number of directories walked, including the database's home.
+  pub directories: usize,
+  pub topics: Vec<TopicReport>,
+  pub stray_files: Vec<StrayFile>,
+}
+
+/// Result of checking one topic.
+pub struct TopicReport {
+  /// The topic's path within the database, such as `\orders\open`.
+  pub path: String,
+  /// Number of readable records in the topic's log.
+  pub records: usize,
+  pub problems: Vec<Problem>,
+  /// Reason the topic could not be checked at all, if it could not.
+  pub error: Option<String>,
+}
+
+/// A problem found in a topic's log.
+pub struct Problem {
+  /// Line (or frame) the problem was found at, preceded by the segment for
+  /// segmented topics.
+  pub location: String,
+  /// Byte offset of the problem in its file.
+  pub offset: usize,
+  pub description: String,
+}
+
+/// A file or directory that is not part of any topic or directory.
+pub struct StrayFile {
+  pub path: PathBuf,
+  pub reason: String,
+}
+
+impl DatabaseReport {
+  pub fn new() -> DatabaseReport {
+    DatabaseReport {
+      directories: 0,
+      topics: Vec::new(),
+      stray_files: Vec::new(),
+    }
+  }
+
+  /// True when no topic has a problem and there are no stray files.
+  pub fn is_clean(&self) -> bool {
+    self.stray_files.is_empty()
+      && self
+        .topics
+        .iter()
+        .all(|topic| topic.problems.is_empty() && topic.error.is_none())
+  }
+
+  /// Number of topic problems, counting a topic that could not be checked
+  /// as one.
+  pub fn problem_count(&self) -> usize {
+    self
+      .topics
+      .iter()
+      .map(|topic| topic.problems.len() + topic.error.iter().count())
+      .sum()
+  }
+
+  /// The report as rows of command output: one per problem or stray file,
+  /// followed by a summary.
+  pub fn rows(&self) -> Vec<(String, String)> {
+    let mut items: Vec<(String, String)> = Vec::new();
+    for topic in &self.topics {
+      if let Some(error) = &topic.error {
+        items.push((topic.path.to_string(), error.to_string()));
+      }
+      for problem in &topic.problems {
+        let location = format!("{} {}", topic.path, problem.location);
+        let description = format!("{} (byte {})", problem.description, problem.offset);
+        items.push((location, description));
+      }
+    }
+    for stray in &self.stray_files {
+      items.push((stray.path.display().to_string(), stray.reason.to_string()));
+    }
+    let summary = format!(
+      "{} directories, {} topics, {} problem(s), {} stray file(s)",
+      self.directories,
+      self.topics.len(),
+      self.problem_count(),
+      self.stray_files.len()
+    );
+    items.push(("".to_string(), summary));
+    items
+  }
+}
+
+impl Default for DatabaseReport {
+  fn default() -> DatabaseReport {
+    DatabaseReport::new()
+  }
+}
+
+/// Checks every directory and topic under `db_home`.
+pub fn check_database(db_home: &str, config: &EngineConfig) -> DatabaseReport {
+  let mut report = DatabaseReport::new();
+  DirectoryContext::new(db_home, "\\", config).check(&mut report);
+  report
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+/// When writes to a topic are flushed from the operating system's cache to
+/// disk. Until then an acknowledged write can be lost if the machine
+/// crashes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Durability {
+  /// Never sync; the operating system writes the data out in its own time.
+  None,
+  /// Sync every write before it is acknowledged.
+  EveryWrite,
+  /// Sync at most once per interval, as a group commit of every write made
+  /// since the last sync. A write that finds the interval has passed is
+  /// written out and synced along with the writes before it. Writes after
+  /// the last sync are synced by `DBEngine::maintain` once the interval has
+  /// passed, or when the topic is closed; the host must call `maintain`
+  /// regularly for the interval to bound how long a write stays unsynced.
+  Interval(Duration),
+}
+
+impl Durability {
+  pub fn name(&self) -> String {
+    match self {
+      Durability::None => "NONE".to_string(),
+      Durability::EveryWrite => "EVERY_WRITE".to_string(),
+      Durability::Interval(interval) => format!("INTERVAL {}ms", interval.as_millis()),
+    }
+  }
+}
+
+/// Settings that control how the engine manages the files in the database.
+#[derive(Clone)]
+pub struct EngineConfig {
+  /// When a topic is opened and its file ends in a damaged record (for
+  /// example a write torn by a crash), cut the file back to the last good
+  /// record. When false the damage is only reported and the topic is opened
+  /// without accepting writes.
+  pub truncate_damaged_tail: bool,
+  /// Number of compaction backups kept for each topic. Older backups are
+  /// removed after a compaction. `None` keeps every backup.
+  pub backup_keep_count: Option<usize>,
+  /// Age after which compaction backups are removed. `None` keeps backups
+  /// regardless of age.
+  pub backup_max_age: Option<Duration>,
+  /// Compact a topic when it is closed if at least this fraction of its
+  /// records are dead (deleted or superseded). `None` disables it.
+  pub auto_compact_dead_ratio: Option<f64>,
+  /// Compact a topic when it is closed if it has dead records and its file
+  /// has grown to at least this many bytes. `None` disables it.
+  pub auto_compact_file_size: Option<u64>,
+  /// Size in bytes at which a segmented topic starts a new segment.
+  pub segment_size: u64,
+  /// Write a snapshot of a topic's records once this many records have been
+  /// written or replayed since the last one, so opening the topic only
+  /// replays the log after it. `None` disables snapshots.
+  pub snapshot_interval: Option<usize>,
+  /// When topic writes are synced to disk.
+  pub durability: Durability,
+  /// Bytes of records an open topic holds in memory before writing them to
+  /// its file. Held records are written out when the buffer fills, when a
+  /// sync is due, before the topic's files are read and when the topic is
+  /// closed, but are lost if the engine's process crashes. 0 writes each
+  /// record as it is made.
+  pub write_buffer: usize,
+}
+
+impl Default for EngineConfig {
+  fn default() -> EngineConfig {
+    EngineConfig {
+      truncate_damaged_tail: false,
+      backup_keep_count: None,
+      backup_max_age: None,
+      auto_compact_dead_ratio: None,
+      auto_compact_file_size: None,
+      segment_size: 16 * 1024 * 1024,
+      snapshot_interval: Some(10_000),
+      durability: Durability::None,
+      write_buffer: 0,
+    }
+  }
+}
@@ -1,12 +1,16 @@
+use crate::check::{self, DatabaseReport, StrayFile};
+use crate::config::EngineConfig;
 use crate::dbprocess::ContextController;
 use crate::dbprocess::ContextProcess;
 use crate::dbprocess::DBResponse;
+use crate::migrate::MigrationReport;
+use crate::search::{self, SearchResults};
+use crate::storage::{self, BACKUP_STAMP, SEGMENTED_EXTENSION};
 use crate::topics::TopicController;
-use std::collections::HashMap;
+use chrono::prelude::*;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(PartialEq, Eq, Hash)]
 enum Target {
   Topic,
   Directory,
@@ -17,6 +21,8 @@ struct Request {
   command: String,
   target: Target,
   arguments: Option<String>,
+  /// Point in a topic's history given by an `AS OF` clause.
+  as_of: Option<String>,
 }
 
 /// Manages directories in the database
@@ -24,13 +30,15 @@ pub struct DirectoryController {
   /// Location of the database
   pub db_home: String,
   pub relative_path: String,
+  config: EngineConfig,
 }
 
 impl DirectoryController {
-  pub fn new(db_home: &str, relative_path: &str) -> DirectoryController {
+  pub fn new(db_home: &str, relative_path: &str, config: &EngineConfig) -> DirectoryController {
     DirectoryController {
       db_home: db_home.to_string(),
       relative_path: relative_path.to_string(),
+      config: config.clone(),
     }
   }
 
@@ -42,80 +50,209 @@ impl DirectoryController {
     let directory_path = self.directory_path(directory_id);
     Path::new(&directory_path).exists()
   }
+
+  /// Names of the subdirectories of the controller's directory, in name
+  /// order. Symbolic links are not followed.
+  fn directory_ids(&self) -> std::io::Result<Vec<String>> {
+    let current_dir = format!("{}{}", self.db_home, self.relative_path);
+    let mut directory_ids: Vec<String> = Vec::new();
+    for entry in fs::read_dir(current_dir)? {
+      let entry = entry?;
+      let is_topic = entry
+        .path()
+        .extension()
+        .is_some_and(|extension| extension == SEGMENTED_EXTENSION);
+      if entry.file_type()?.is_dir() && !is_topic {
+        directory_ids.push(entry.file_name().to_string_lossy().to_string());
+      }
+    }
+    directory_ids.sort();
+    Ok(directory_ids)
+  }
+
+  /// Context of one of the subdirectories.
+  fn subdirectory(&self, directory_id: &str) -> DirectoryContext {
+    let new_path = format!("{}{}\\", self.relative_path, directory_id);
+    DirectoryContext::new(&self.db_home, &new_path, &self.config)
+  }
+
+  /// Counts the directory, reports the files in it that belong to no topic
+  /// and checks each subdirectory. Symbolic links are not followed.
+  fn check(&self, report: &mut DatabaseReport) {
+    report.directories += 1;
+    let current_dir = format!("{}{}", self.db_home, self.relative_path);
+    let unreadable = |error: std::io::Error| StrayFile {
+      path: PathBuf::from(&current_dir),
+      reason: format!("unreadable directory: {}", error),
+    };
+    match storage::stray_files(Path::new(&current_dir)) {
+      Ok(strays) => {
+        for (path, reason) in strays {
+          report.stray_files.push(StrayFile { path, reason });
+        }
+      }
+      Err(error) => {
+        report.stray_files.push(unreadable(error));
+        return;
+      }
+    }
+    let directory_ids = match self.directory_ids() {
+      Ok(directory_ids) => directory_ids,
+      Err(error) => {
+        report.stray_files.push(unreadable(error));
+        return;
+      }
+    };
+    for directory_id in directory_ids {
+      self.subdirectory(&directory_id).check(report);
+    }
+  }
+
+  /// Migrates the topics of each subdirectory.
+  fn migrate_all(&self, stamp: &str, report: &mut MigrationReport) {
+    let directory_ids = match self.directory_ids() {
+      Ok(directory_ids) => directory_ids,
+      Err(_) => return,
+    };
+    for directory_id in directory_ids {
+      self.subdirectory(&directory_id).migrate_all(stamp, report);
+    }
+  }
+
+  /// Searches the topics of each subdirectory.
+  fn search(&self, terms: &[String], results: &mut SearchResults) {
+    let directory_ids = match self.directory_ids() {
+      Ok(directory_ids) => directory_ids,
+      Err(_) => return,
+    };
+    for directory_id in directory_ids {
+      self.subdirectory(&directory_id).search_all(terms, results);
+    }
+  }
 }
 
 pub struct DirectoryContext {
   pub db_home: String,
   pub relative_path: String,
-  controller_map: HashMap<Target, Box<dyn ContextController>>,
+  topics: TopicController,
+  directories: DirectoryController,
+  config: EngineConfig,
 }
 
 impl DirectoryContext {
-  pub fn new(db_home: &str, relative_path: &str) -> DirectoryContext {
-    let mut directory_context = DirectoryContext {
+  pub fn new(db_home: &str, relative_path: &str, config: &EngineConfig) -> DirectoryContext {
+    debug!("creating topic controller for path {}", relative_path);
+    DirectoryContext {
       db_home: db_home.to_string(),
       relative_path: relative_path.to_string(),
-      controller_map: HashMap::new(),
-    };
-    debug!("creating topic controller for path {}", relative_path);
-    directory_context.controller_map.insert(
-      Target::Topic,
-      Box::new(TopicController::new(&db_home, &relative_path)),
-    );
-    directory_context.controller_map.insert(
-      Target::Directory,
-      Box::new(DirectoryController::new(&db_home, &relative_path)),
-    );
-    directory_context
+      topics: TopicController::new(db_home, relative_path, config),
+      directories: DirectoryController::new(db_home, relative_path, config),
+      config: config.clone(),
+    }
+  }
+
+  /// Controller for the kind of item a request names.
+  fn controller(&self, target: &Target) -> Option<&dyn ContextController> {
+    match target {
+      Target::Topic => Some(&self.topics),
+      Target::Directory => Some(&self.directories),
+      Target::None => None,
+    }
+  }
+
+  /// Id of the topic a command that only applies to topics is run on, or
+  /// why the request cannot be run when it names something else.
+  fn topic_id<'a>(request: &'a Request, command: &str) -> Result<&'a str, String> {
+    match (&request.target, &request.arguments) {
+      (Target::Topic, Some(topic_id)) => Ok(topic_id),
+      (Target::Topic, None) => Err(format!("{} requires an id", command)),
+      (Target::Directory, _) => Err(format!("{} is not applicable to directories", command)),
+      (Target::None, _) => Err("Valid type required. (expected \"TOPIC\")".to_string()),
+    }
+  }
+
+  /// Adds the directory, its topics and its subdirectories to the report.
+  pub fn check(&self, report: &mut DatabaseReport) {
+    self.topics.check(report);
+    self.directories.check(report);
+  }
+
+  /// Migrates the topics in the directory and its subdirectories.
+  pub fn migrate_all(&self, stamp: &str, report: &mut MigrationReport) {
+    self.topics.migrate_all(stamp, report);
+    self.directories.migrate_all(stamp, report);
+  }
+
+  /// Searches the topics in the directory and its subdirectories.
+  pub fn search_all(&self, terms: &[String], results: &mut SearchResults) {
+    self.topics.search(terms, results);
+    self.directories.search(terms, results);
   }
 
   fn parse_request(request: &str) -> Result<Request, &'static str> {
-    if request.len() == 0 {
+    if request.is_empty() {
       return Err("nothing to parse");
     }
     let tokens: Vec<&str> = request.split(' ').collect();
-    let command = tokens.get(0).unwrap();
+    let command = tokens.first().unwrap();
     if tokens.len() == 1 {
       return Ok(Request {
         command: command.to_uppercase(),
         target: Target::None,
         arguments: None,
+        as_of: None,
       });
     }
     let target_token = tokens.get(1).unwrap();
     let target = target_token.to_uppercase();
-    let arguments = if tokens.len() > 2 {
-      Some(tokens[2..].join(" "))
-    } else {
-      None
-    };
+    let (arguments, as_of) = DirectoryContext::split_as_of(&tokens[2..]);
     match target.as_str() {
-      "TOPIC" => {
-        return Ok(Request {
-          command: command.to_uppercase(),
-          target: Target::Topic,
-          arguments: arguments,
-        })
-      }
-      "DIRECTORY" => {
-        return Ok(Request {
-          command: command.to_uppercase(),
-          target: Target::Directory,
-          arguments: arguments,
-        })
-      }
-      _ => {
-        return Ok(Request {
-          command: command.to_uppercase(),
-          target: Target::None,
-          arguments: Some(tokens[1..].join(" ")),
-        })
-      }
+      "TOPIC" => Ok(Request {
+        command: command.to_uppercase(),
+        target: Target::Topic,
+        arguments,
+        as_of,
+      }),
+      "DIRECTORY" => Ok(Request {
+        command: command.to_uppercase(),
+        target: Target::Directory,
+        arguments,
+        as_of,
+      }),
+      _ => Ok(Request {
+        command: command.to_uppercase(),
+        target: Target::None,
+        arguments: Some(tokens[1..].join(" ")),
+        as_of: None,
+      }),
     }
   }
 
+  /// Splits an `AS OF <point>` clause from the arguments that precede it.
+  fn split_as_of(tokens: &[&str]) -> (Option<String>, Option<String>) {
+    let clause = tokens
+      .windows(2)
+      .position(|pair| pair[0].eq_ignore_ascii_case("AS") && pair[1].eq_ignore_ascii_case("OF"));
+    let (argument_tokens, as_of) = match clause {
+      Some(index) => (&tokens[..index], Some(tokens[index + 2..].join(" "))),
+      None => (tokens, None),
+    };
+    let arguments = if argument_tokens.is_empty() {
+      None
+    } else {
+      Some(argument_tokens.join(" "))
+    };
+    (arguments, as_of)
+  }
+
   fn list(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    match self.controller_map.get(&request.target) {
+    if let (Target::None, Some(arguments)) = (&request.target, &request.arguments) {
+      let (option, topic_id) = arguments.split_once(' ').unwrap_or((arguments, ""));
+      if option.eq_ignore_ascii_case("BACKUPS") {
+        return self.list_backups(topic_id.trim());
+      }
+    }
+    match self.controller(&request.target) {
       Some(controller) => {
         let list = controller.list();
         DBResponse::Data(list)
@@ -127,9 +264,9 @@ impl DirectoryContext {
   }
 
   fn create(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    match self.controller_map.get(&request.target) {
+    match self.controller(&request.target) {
       Some(controller) => match &request.arguments {
-        Some(arguments) => match controller.create(&arguments) {
+        Some(arguments) => match controller.create(arguments) {
           Ok(message) => DBResponse::ROk(message.to_string()),
           Err(message) => DBResponse::Error(message.to_string()),
         },
@@ -141,6 +278,69 @@ impl DirectoryContext {
     }
   }
 
+  /// Lists the compaction backups of a topic, newest first.
+  fn list_backups(&self, topic_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if topic_id.is_empty() {
+      return DBResponse::Invalid("List backups requires a topic id".to_string());
+    }
+    self.topics.list_backups(topic_id)
+  }
+
+  /// Runs `CHECK DATABASE` over the whole database, whichever directory it
+  /// is run from.
+  fn check_database(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    match (&request.target, &request.arguments) {
+      (Target::None, Some(arguments)) if arguments.eq_ignore_ascii_case("DATABASE") => {
+        let report = check::check_database(&self.db_home, &self.config);
+        DBResponse::Data(report.rows())
+      }
+      _ => DBResponse::Invalid("Valid type required. (expected \"DATABASE\")".to_string()),
+    }
+  }
+
+  /// Runs `MIGRATE TOPIC <id>`, or `MIGRATE DATABASE` over every topic in
+  /// the database, whichever directory it is run from.
+  fn migrate(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if let (Target::None, Some(arguments)) = (&request.target, &request.arguments) {
+      if arguments.eq_ignore_ascii_case("DATABASE") {
+        let stamp = Local::now().format(BACKUP_STAMP).to_string();
+        let mut report = MigrationReport::new();
+        DirectoryContext::new(&self.db_home, "\\", &self.config).migrate_all(&stamp, &mut report);
+        return DBResponse::Data(report.rows());
+      }
+    }
+    match (&request.target, &request.arguments) {
+      (Target::Topic, Some(topic_id)) => self.topics.migrate(topic_id),
+      (Target::Topic, None) => DBResponse::Invalid("Migrate requires an id".to_string()),
+      (Target::Directory, _) => {
+        DBResponse::Invalid("Migrate is not applicable to directories".to_string())
+      }
+      (Target::None, _) => {
+        DBResponse::Invalid("Valid type required. (expected \"TOPIC\" or \"DATABASE\")".to_string())
+      }
+    }
+  }
+
+  /// Searches every topic beneath the directory, listing the records found
+  /// best match first, each under its topic's path. The records are ranked
+  /// together, by the statistics of all the topics searched.
+  fn search(&self, request_text: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let query = request_text.split_once(' ').map_or("", |(_, query)| query);
+    let terms = search::terms(query);
+    if terms.is_empty() {
+      return DBResponse::Invalid("SEARCH requires terms to search for".to_string());
+    }
+    let mut results = SearchResults::default();
+    self.search_all(&terms, &mut results);
+    results.rank();
+    let list: Vec<(String, String)> = results
+      .hits
+      .into_iter()
+      .map(|hit| (format!("{} {}", hit.topic, hit.id), hit.content))
+      .collect();
+    DBResponse::Data(list)
+  }
+
   fn status(&self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
     let mut items: Vec<(String, String)> = Vec::new();
     let path = self.db_home.clone();
@@ -150,9 +350,9 @@ impl DirectoryContext {
   }
 
   fn drop(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    match self.controller_map.get(&request.target) {
+    match self.controller(&request.target) {
       Some(controller) => match &request.arguments {
-        Some(arguments) => match controller.drop_item(&arguments) {
+        Some(arguments) => match controller.drop_item(arguments) {
           Ok(message) => DBResponse::ROk(message.to_string()),
           Err(message) => DBResponse::Error(message.to_string()),
         },
@@ -165,9 +365,15 @@ impl DirectoryContext {
   }
 
   fn open(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    match self.controller_map.get(&request.target) {
+    if let Some(as_of) = &request.as_of {
+      return match DirectoryContext::topic_id(request, "AS OF") {
+        Ok(topic_id) => self.topics.open_as_of(topic_id, as_of),
+        Err(message) => DBResponse::Invalid(message),
+      };
+    }
+    match self.controller(&request.target) {
       Some(controller) => match &request.arguments {
-        Some(arguments) => controller.open(&arguments),
+        Some(arguments) => controller.open(arguments),
         _ => DBResponse::Invalid("Open requires an id".to_string()),
       },
       None => DBResponse::Invalid(
@@ -177,9 +383,9 @@ impl DirectoryContext {
   }
 
   fn compact(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    match self.controller_map.get(&request.target) {
+    match self.controller(&request.target) {
       Some(controller) => match &request.arguments {
-        Some(arguments) => controller.compact(&arguments),
+        Some(arguments) => controller.compact(arguments),
         _ => DBResponse::Invalid("Compact requires an id".to_string()),
       },
       None => DBResponse::Invalid(
@@ -187,6 +393,27 @@ impl DirectoryContext {
       ),
     }
   }
+
+  fn restore(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    match DirectoryContext::topic_id(request, "Restore") {
+      Ok(args) => self.topics.restore(args),
+      Err(message) => DBResponse::Invalid(message),
+    }
+  }
+
+  fn verify(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    match DirectoryContext::topic_id(request, "Verify") {
+      Ok(topic_id) => self.topics.verify(topic_id),
+      Err(message) => DBResponse::Invalid(message),
+    }
+  }
+
+  fn repair(&self, request: &Request) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    match DirectoryContext::topic_id(request, "Repair") {
+      Ok(topic_id) => self.topics.repair(topic_id),
+      Err(message) => DBResponse::Invalid(message),
+    }
+  }
 }
 
 impl ContextProcess for DirectoryContext {
@@ -196,12 +423,21 @@ impl ContextProcess for DirectoryContext {
 
   fn process(&mut self, request_text: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
     match DirectoryContext::parse_request(request_text) {
+      Ok(parsed) if parsed.as_of.is_some() && parsed.command != "OPEN" => {
+        DBResponse::Invalid("AS OF is only valid with OPEN".to_string())
+      }
       Ok(parsed) => match parsed.command.as_str() {
         "LIST" => self.list(&parsed),
         "STATUS" => self.status(),
         "CREATE" => self.create(&parsed),
         "OPEN" => self.open(&parsed),
         "COMPACT" => self.compact(&parsed),
+        "RESTORE" => self.restore(&parsed),
+        "VERIFY" => self.verify(&parsed),
+        "REPAIR" => self.repair(&parsed),
+        "CHECK" => self.check_database(&parsed),
+        "MIGRATE" => self.migrate(&parsed),
+        "SEARCH" => self.search(request_text),
         "DROP" => self.drop(&parsed),
         "EXIT" => DBResponse::Exit,
         "CLOSE" => {
@@ -213,14 +449,14 @@ impl ContextProcess for DirectoryContext {
         }
         _ => DBResponse::Unknown(parsed.command),
       },
-      Err(message) => return DBResponse::Error(message.to_string()),
+      Err(message) => DBResponse::Error(message.to_string()),
     }
   }
 }
 
 impl ContextController for DirectoryController {
   fn create(&self, directory_id: &str) -> Result<String, String> {
-    if self.directory_exists(&directory_id) {
+    if self.directory_exists(directory_id) {
       let message = format!("The directory {} already exists.", directory_id);
       return Err(message);
     }
@@ -243,8 +479,11 @@ impl ContextController for DirectoryController {
     for file_result in files {
       let file = file_result.unwrap();
       let metadata = file.metadata();
-      if metadata.unwrap().is_dir() {
-        let path = file.path();
+      let path = file.path();
+      let is_topic = path
+        .extension()
+        .is_some_and(|extension| extension == SEGMENTED_EXTENSION);
+      if metadata.unwrap().is_dir() && !is_topic {
         let dir_name = path.file_stem().unwrap().to_str().unwrap();
         items.push(("".to_string(), dir_name.to_string()));
       }
@@ -253,7 +492,7 @@ impl ContextController for DirectoryController {
   }
 
   fn drop_item(&self, directory_id: &str) -> Result<String, String> {
-    if !self.directory_exists(&directory_id) {
+    if !self.directory_exists(directory_id) {
       let message = format!("The directory {} does not exist.", directory_id);
       return Err(message);
     }
@@ -270,12 +509,12 @@ impl ContextController for DirectoryController {
   }
 
   fn open(&self, directory_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    if !self.directory_exists(&directory_id) {
+    if !self.directory_exists(directory_id) {
       let message = format!("{} does not exist.", directory_id);
       return DBResponse::Error(message);
     }
     let new_path = format!("{}{}\\", self.relative_path, directory_id);
-    let directory = DirectoryContext::new(&self.db_home, &new_path);
+    let directory = DirectoryContext::new(&self.db_home, &new_path, &self.config);
     DBResponse::OpenContext((Box::new(directory), new_path.to_string()))
   }
 
@@ -0,0 +1,168 @@
+//! Records of `JSON` topics, whose content is a JSON object, and the
+//! conditions `FIND WHERE` selects them by.
+//!
+//! A condition names a field, an operator and a value:
+//!
+//! ```text
+//! FIND WHERE <field> =|<|>|CONTAINS <value>
+//! ```
+//!
+//! Fields of nested objects are named with dots (`address.city`). The value
+//! is read as JSON when it parses as JSON and as a string otherwise, so
+//! `name = Ada` and `name = "Ada"` are the same condition. Numbers compare
+//! by value and strings in character order. `CONTAINS` matches a string
+//! field holding the value and an array field with the value as one of its
+//! items. A record without the field never matches.
+
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// Parses the content of a record in a `JSON` topic.
+pub fn parse(content: &str) -> Result<Value, String> {
+  match serde_json::from_str::<Value>(content) {
+    Ok(document) if document.is_object() => Ok(document),
+    Ok(_) => Err("Content must be a JSON object.".to_string()),
+    Err(error) => Err(format!("Content is not valid JSON: {}.", error)),
+  }
+}
+
+/// The value of a field, named with dots for nested objects.
+pub fn field<'a>(document: &'a Value, name: &str) -> Option<&'a Value> {
+  name
+    .split('.')
+    .try_fold(document, |value, part| value.as_object()?.get(part))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operator {
+  Equal,
+  Less,
+  Greater,
+  Contains,
+}
+
+/// Condition of a `FIND WHERE` command.
+pub struct Condition {
+  pub field: String,
+  pub operator: Operator,
+  pub value: Value,
+}
+
+impl Condition {
+  /// Parses the tokens after `WHERE`: a field, an operator and a value,
+  /// which takes up the rest of the tokens.
+  pub fn parse(tokens: &[&str]) -> Result<Condition, String> {
+    if tokens.len() < 3 {
+      return Err("Expected a field, an operator and a value.".to_string());
+    }
+    let operator = match tokens[1].to_uppercase().as_str() {
+      "=" => Operator::Equal,
+      "<" => Operator::Less,
+      ">" => Operator::Greater,
+      "CONTAINS" => Operator::Contains,
+      other => {
+        return Err(format!(
+          "Unknown operator {}. (expected =, <, > or CONTAINS)",
+          other
+        ))
+      }
+    };
+    Ok(Condition {
+      field: tokens[0].to_string(),
+      operator,
+      value: value(&tokens[2..].join(" ")),
+    })
+  }
+
+  pub fn matches(&self, document: &Value) -> bool {
+    let field = match field(document, &self.field) {
+      Some(field) => field,
+      None => return false,
+    };
+    match self.operator {
+      Operator::Equal => equal(field, &self.value),
+      Operator::Less => compare(field, &self.value) == Some(Ordering::Less),
+      Operator::Greater => compare(field, &self.value) == Some(Ordering::Greater),
+      Operator::Contains => match (field, &self.value) {
+        (Value::String(text), Value::String(part)) => text.contains(part.as_str()),
+        (Value::Array(items), value) => items.iter().any(|item| equal(item, value)),
+        _ => false,
+      },
+    }
+  }
+}
+
+/// Reads a value given in a command as JSON, or as a string if it is not
+/// valid JSON.
+fn value(text: &str) -> Value {
+  serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+/// True when two values are equal, comparing numbers by value.
+fn equal(a: &Value, b: &Value) -> bool {
+  compare(a, b) == Some(Ordering::Equal) || a == b
+}
+
+/// Order of two numbers, two strings or two booleans. Other values are not
+/// ordered.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+  match (a, b) {
+    (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+    (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn matches(condition: &str, content: &str) -> bool {
+    let tokens: Vec<&str> = condition.split_whitespace().collect();
+    let condition = Condition::parse(&tokens).expect("condition parses");
+    condition.matches(&parse(content).expect("content is a JSON object"))
+  }
+
+  const DOCUMENT: &str = r#"{"name": "Ada Lovelace", "born": 1815, "tags": ["math", 1843],
+    "address": {"city": "London"}, "active": false}"#;
+
+  #[test]
+  fn equal_reads_values_as_json_or_strings() {
+    assert!(matches("name = Ada Lovelace", DOCUMENT));
+    assert!(matches(r#"name = "Ada Lovelace""#, DOCUMENT));
+    assert!(matches("born = 1815.0", DOCUMENT));
+    assert!(matches("active = false", DOCUMENT));
+    assert!(matches("address.city = London", DOCUMENT));
+    assert!(!matches("born = \"1815\"", DOCUMENT));
+    assert!(!matches("address.street = London", DOCUMENT));
+  }
+
+  #[test]
+  fn less_and_greater_order_numbers_and_strings() {
+    assert!(matches("born < 1900", DOCUMENT));
+    assert!(matches("born > 1814.5", DOCUMENT));
+    assert!(!matches("born > 1815", DOCUMENT));
+    assert!(matches("name > Ada", DOCUMENT));
+    assert!(matches("name < Babbage", DOCUMENT));
+    assert!(!matches("name < 1900", DOCUMENT));
+    assert!(!matches("missing < 1900", DOCUMENT));
+  }
+
+  #[test]
+  fn contains_matches_substrings_and_array_items() {
+    assert!(matches("name CONTAINS Love", DOCUMENT));
+    assert!(matches("tags contains math", DOCUMENT));
+    assert!(matches("tags CONTAINS 1843", DOCUMENT));
+    assert!(!matches("tags CONTAINS mat", DOCUMENT));
+    assert!(!matches("born CONTAINS 18", DOCUMENT));
+  }
+
+  #[test]
+  fn parse_rejects_incomplete_conditions_and_unknown_operators() {
+    assert!(Condition::parse(&["name", "="]).is_err());
+    assert!(Condition::parse(&["name", "LIKE", "Ada"]).is_err());
+    assert!(parse("[1, 2]").is_err());
+    assert!(parse("{").is_err());
+  }
+}
@@ -0,0 +1,265 @@
+//! Indexes on fields of the records of `JSON` topics.
+//!
+//! An index maps each value a field holds to the records holding it, so
+//! `FIND WHERE <field> = <value>` can go straight to the matching records
+//! instead of reading every record in the topic. Values are indexed the way
+//! `FIND` compares them: numbers by value, so `1` and `1.0` share an entry.
+//! Records without the field are not indexed.
+//!
+//! The indexes a topic has are kept in a file of its own next to the
+//! topic's records, one index per line:
+//!
+//! ```text
+//! LISTDB-INDEXES 1
+//! <name> <field>
+//! ```
+//!
+//! The entries themselves are built from the topic's records as it is
+//! loaded and kept up to date as records are written.
+
+use crate::documents;
+use crate::storage;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const INDEXES_MAGIC: &str = "LISTDB-INDEXES";
+const INDEXES_VERSION: u32 = 1;
+
+pub struct Index {
+  pub name: String,
+  /// Field the index is on, named with dots for nested objects.
+  pub field: String,
+  /// Ids of the records holding each value, by the value's key.
+  entries: HashMap<String, HashSet<String>>,
+}
+
+impl Index {
+  pub fn new(name: &str, field: &str) -> Index {
+    Index {
+      name: name.to_string(),
+      field: field.to_string(),
+      entries: HashMap::new(),
+    }
+  }
+
+  /// Number of distinct values in the index.
+  pub fn values(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Ids of the records whose field equals `value`.
+  pub fn lookup(&self, value: &Value) -> Vec<&str> {
+    match self.entries.get(&key(value)) {
+      Some(ids) => ids.iter().map(|id| id.as_str()).collect(),
+      None => Vec::new(),
+    }
+  }
+
+  fn insert(&mut self, id: &str, document: &Value) {
+    if let Some(value) = documents::field(document, &self.field) {
+      let ids = self.entries.entry(key(value)).or_default();
+      ids.insert(id.to_string());
+    }
+  }
+
+  fn remove(&mut self, id: &str, document: &Value) {
+    if let Some(value) = documents::field(document, &self.field) {
+      let key = key(value);
+      if let Some(ids) = self.entries.get_mut(&key) {
+        ids.remove(id);
+        if ids.is_empty() {
+          self.entries.remove(&key);
+        }
+      }
+    }
+  }
+}
+
+/// The indexes of a topic.
+#[derive(Default)]
+pub struct Indexes {
+  pub indexes: Vec<Index>,
+}
+
+impl Indexes {
+  /// Reads the indexes a topic has, with no entries. A topic without an
+  /// index file has no indexes.
+  pub fn read(path: &Path) -> Result<Indexes, String> {
+    let contents = match fs::read_to_string(path) {
+      Ok(contents) => contents,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Indexes::default()),
+      Err(error) => return Err(error.to_string()),
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(&format!("{} {}", INDEXES_MAGIC, INDEXES_VERSION)) {
+      return Err("invalid index file header".to_string());
+    }
+    let mut indexes: Vec<Index> = Vec::new();
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+      match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        [name, field] => indexes.push(Index::new(name, field)),
+        _ => return Err(format!("invalid index \"{}\"", line)),
+      }
+    }
+    Ok(Indexes { indexes })
+  }
+
+  /// Writes the indexes to a temporary file and moves it into place. With
+  /// no indexes left the file is removed.
+  pub fn write(&self, path: &Path) -> io::Result<()> {
+    if self.indexes.is_empty() {
+      return match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+      };
+    }
+    let mut contents = format!("{} {}\n", INDEXES_MAGIC, INDEXES_VERSION);
+    for index in &self.indexes {
+      contents.push_str(&format!("{} {}\n", index.name, index.field));
+    }
+    storage::write_replacing(path, contents)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.indexes.is_empty()
+  }
+
+  pub fn named(&self, name: &str) -> Option<&Index> {
+    self.indexes.iter().find(|index| index.name == name)
+  }
+
+  /// The index on `field`, if there is one.
+  pub fn on(&self, field: &str) -> Option<&Index> {
+    self.indexes.iter().find(|index| index.field == field)
+  }
+
+  /// Rebuilds every index from the records of a topic, given as their ids
+  /// and content.
+  pub fn rebuild<'a>(&mut self, records: impl Iterator<Item = (&'a str, &'a str)>) {
+    for index in self.indexes.iter_mut() {
+      index.entries.clear();
+    }
+    for (id, content) in records {
+      self.insert(id, content);
+    }
+  }
+
+  /// Adds a record's content to every index.
+  pub fn insert(&mut self, id: &str, content: &str) {
+    if let Ok(document) = documents::parse(content) {
+      for index in self.indexes.iter_mut() {
+        index.insert(id, &document);
+      }
+    }
+  }
+
+  /// Removes a record's content from every index.
+  pub fn remove(&mut self, id: &str, content: &str) {
+    if let Ok(document) = documents::parse(content) {
+      for index in self.indexes.iter_mut() {
+        index.remove(id, &document);
+      }
+    }
+  }
+}
+
+/// Key of a value in an index. Numbers are keyed by value so that equal
+/// numbers written differently share a key.
+fn key(value: &Value) -> String {
+  match value {
+    Value::Number(number) => match number.as_f64() {
+      Some(number) => format!("n:{}", number),
+      None => format!("j:{}", value),
+    },
+    Value::String(text) => format!("s:{}", text),
+    _ => format!("j:{}", value),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::documents::{Condition, Operator};
+
+  const RECORDS: [(&str, &str); 6] = [
+    ("a", r#"{"age": 36, "city": "London"}"#),
+    ("b", r#"{"age": 36.0, "city": "Paris"}"#),
+    ("c", r#"{"age": "36", "city": "London"}"#),
+    ("d", r#"{"age": 41, "city": {"name": "London"}}"#),
+    ("e", r#"{"name": "no age"}"#),
+    ("f", r#"{"age": [36]}"#),
+  ];
+
+  fn indexes(records: &[(&str, &str)]) -> Indexes {
+    let mut indexes = Indexes {
+      indexes: vec![Index::new("by_age", "age"), Index::new("by_city", "city")],
+    };
+    indexes.rebuild(records.iter().copied());
+    indexes
+  }
+
+  fn looked_up(indexes: &Indexes, field: &str, value: &str) -> Vec<String> {
+    let value = serde_json::from_str(value).expect("value is JSON");
+    let mut ids: Vec<String> = indexes
+      .on(field)
+      .expect("field is indexed")
+      .lookup(&value)
+      .into_iter()
+      .map(|id| id.to_string())
+      .collect();
+    ids.sort();
+    ids
+  }
+
+  fn scanned(records: &[(&str, &str)], field: &str, value: &str) -> Vec<String> {
+    let condition = Condition {
+      field: field.to_string(),
+      operator: Operator::Equal,
+      value: serde_json::from_str(value).expect("value is JSON"),
+    };
+    records
+      .iter()
+      .filter(|(_, content)| condition.matches(&documents::parse(content).unwrap()))
+      .map(|(id, _)| id.to_string())
+      .collect()
+  }
+
+  #[test]
+  fn lookup_finds_what_a_scan_finds() {
+    let indexes = indexes(&RECORDS);
+    for (field, value) in [
+      ("age", "36"),
+      ("age", "36.0"),
+      ("age", "\"36\""),
+      ("age", "41"),
+      ("age", "[36]"),
+      ("age", "50"),
+      ("city", "\"London\""),
+      ("city", r#"{"name": "London"}"#),
+    ] {
+      assert_eq!(
+        looked_up(&indexes, field, value),
+        scanned(&RECORDS, field, value),
+        "{} = {}",
+        field,
+        value
+      );
+    }
+    assert_eq!(looked_up(&indexes, "age", "36"), vec!["a", "b"]);
+  }
+
+  #[test]
+  fn updates_move_records_between_entries() {
+    let mut indexes = indexes(&RECORDS);
+    indexes.remove("a", RECORDS[0].1);
+    indexes.insert("a", r#"{"age": 41}"#);
+    assert_eq!(looked_up(&indexes, "age", "36"), vec!["b"]);
+    assert_eq!(looked_up(&indexes, "age", "41"), vec!["a", "d"]);
+    assert_eq!(looked_up(&indexes, "city", "\"London\""), vec!["c"]);
+    indexes.remove("b", RECORDS[1].1);
+    assert_eq!(indexes.on("age").unwrap().values(), 3);
+  }
+}
@@ -4,14 +4,31 @@ extern crate uuid;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate regex;
+extern crate serde_json;
 
+use check::DatabaseReport;
+use config::EngineConfig;
 use dbprocess::ContextProcess;
 use dbprocess::DBResponse;
 use directories::DirectoryContext;
 use std::collections::VecDeque;
 
+pub mod check;
+pub mod config;
 mod directories;
+mod documents;
+mod indexes;
+mod migrate;
+mod positions;
+mod queries;
+mod records;
+mod schemas;
+mod search;
+mod snapshots;
+mod storage;
 mod topics;
+mod verify;
 
 pub mod dbprocess {
     pub enum DBResponse<T> {
@@ -29,6 +46,9 @@ pub mod dbprocess {
     pub trait ContextProcess {
         fn process(&mut self, command_line: &str) -> DBResponse<(Box<dyn ContextProcess>, String)>;
         fn id(&self) -> String;
+        /// Does work that has come due without a request, such as syncing
+        /// writes whose durability interval has passed.
+        fn maintain(&mut self) {}
     }
 
     pub trait ContextController {
@@ -42,18 +62,43 @@ pub mod dbprocess {
 
 pub struct DBEngine {
     context_stack: VecDeque<Box<dyn ContextProcess>>,
+    db_home: String,
+    config: EngineConfig,
 }
 
 impl DBEngine {
     pub fn new(path: &str) -> DBEngine {
-        let root_context = DirectoryContext::new(&path, "\\");
+        DBEngine::with_config(path, EngineConfig::default())
+    }
+
+    pub fn with_config(path: &str, config: EngineConfig) -> DBEngine {
+        let root_context = DirectoryContext::new(path, "\\", &config);
         let mut db_engine = DBEngine {
             context_stack: VecDeque::new(),
+            db_home: path.to_string(),
+            config,
         };
         db_engine.context_stack.push_front(Box::new(root_context));
         db_engine
     }
 
+    /// Checks every topic in every directory of the database and looks for
+    /// files that belong to none of them. The same check is run by the
+    /// `CHECK DATABASE` command.
+    pub fn check_database(&self) -> DatabaseReport {
+        check::check_database(&self.db_home, &self.config)
+    }
+
+    /// Syncs writes to the open topics whose durability interval has passed.
+    /// With `Durability::Interval` the host should call this at least once
+    /// per interval, so writes are synced within about the interval even
+    /// when no request follows them. Each request also runs it first.
+    pub fn maintain(&mut self) {
+        for context in self.context_stack.iter_mut() {
+            context.maintain();
+        }
+    }
+
     pub fn request(&mut self, db_request: &str) -> DBResponse<String> {
         //TODO Replace following line with debug logging?
         debug!(
@@ -61,6 +106,7 @@ impl DBEngine {
             self.context_stack.len(),
             db_request
         );
+        self.maintain();
         let mut context = self.context_stack.pop_front().unwrap();
         let result = context.process(db_request);
         self.context_stack.push_front(context);
@@ -0,0 +1,151 @@
+//! Upgrading topic files written in an older format to the current one.
+//!
+//! Each file is rewritten record by record into a new file in the current
+//! format, next to the original. Only once every file of the topic has been
+//! rewritten are the originals linked to a backup and the new files renamed
+//! over them, so the topic's files never go missing. Records from formats without sequence numbers are
+//! numbered in the order they appear in the log; the time they were written
+//! is not known and is recorded as such.
+
+use crate::records::{LogReader, CURRENT_VERSION};
+use crate::storage::{backup_path, temporary_path, Storage, MIGRATE_TEMPORARY};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Outcome of migrating a topic.
+pub struct Migration {
+  /// Oldest format version found among the topic's files.
+  pub from_version: u32,
+  /// Number of files rewritten.
+  pub files: usize,
+  /// Number of records rewritten.
+  pub records: u64,
+}
+
+/// Outcome of migrating every topic in a database.
+pub struct MigrationReport {
+  /// Each topic's path within the database, with its migration or the
+  /// reason it could not be migrated.
+  pub topics: Vec<(String, Result<Migration, String>)>,
+}
+
+impl MigrationReport {
+  pub fn new() -> MigrationReport {
+    MigrationReport { topics: Vec::new() }
+  }
+
+  /// The report as rows of command output: one per topic that was
+  /// migrated or failed, followed by a summary.
+  pub fn rows(&self) -> Vec<(String, String)> {
+    let mut items: Vec<(String, String)> = Vec::new();
+    let mut migrated = 0;
+    let mut failed = 0;
+    for (path, result) in &self.topics {
+      match result {
+        Ok(migration) if migration.files == 0 => continue,
+        Ok(migration) => {
+          migrated += 1;
+          let message = format!(
+            "migrated from format {} to {}, {} record(s) rewritten",
+            migration.from_version, CURRENT_VERSION, migration.records
+          );
+          items.push((path.to_string(), message));
+        }
+        Err(message) => {
+          failed += 1;
+          items.push((path.to_string(), format!("not migrated: {}", message)));
+        }
+      }
+    }
+    let summary = format!(
+      "{} topics, {} migrated, {} failed",
+      self.topics.len(),
+      migrated,
+      failed
+    );
+    items.push(("".to_string(), summary));
+    items
+  }
+}
+
+impl Default for MigrationReport {
+  fn default() -> MigrationReport {
+    MigrationReport::new()
+  }
+}
+
+/// Rewrites every file of a topic that is not in the current format,
+/// keeping the originals as a backup taken at `stamp`. Nothing is replaced
+/// if any file has a record that cannot be read, as it would be lost.
+pub fn migrate(storage: &Storage, stamp: &str) -> Result<Migration, String> {
+  let files = storage.files().map_err(|error| error.to_string())?;
+  let mut migration = Migration {
+    from_version: CURRENT_VERSION,
+    files: 0,
+    records: 0,
+  };
+  let mut migrated: Vec<(&PathBuf, PathBuf)> = Vec::new();
+  let mut numbered: u64 = 0;
+  for file in &files {
+    let migrated_file = temporary_path(file, MIGRATE_TEMPORARY);
+    let result = rewrite(file, &migrated_file, numbered);
+    match result {
+      Ok(None) => (),
+      Ok(Some((version, count))) => {
+        migration.from_version = migration.from_version.min(version);
+        migration.records += count;
+        numbered += count;
+        migrated.push((file, migrated_file));
+      }
+      Err(message) => {
+        for (_, migrated_file) in &migrated {
+          let _ = fs::remove_file(migrated_file);
+        }
+        let _ = fs::remove_file(&migrated_file);
+        return Err(format!("{}: {}", file.display(), message));
+      }
+    }
+  }
+  for (file, migrated_file) in migrated {
+    fs::hard_link(file, backup_path(file, stamp)).map_err(|error| error.to_string())?;
+    fs::rename(&migrated_file, file).map_err(|error| error.to_string())?;
+    migration.files += 1;
+  }
+  Ok(migration)
+}
+
+/// Writes the records of `file` to `migrated_file` in the current format,
+/// numbering records without sequence numbers on from `numbered`. Returns
+/// the file's version and the number of records written, or `None` if the
+/// file is already current.
+fn rewrite(file: &Path, migrated_file: &Path, numbered: u64) -> Result<Option<(u32, u64)>, String> {
+  let mut reader = LogReader::open(file, 0)?;
+  if reader.format.is_current() {
+    return Ok(None);
+  }
+  let version = reader.format.version;
+  let format = reader.format.upgraded();
+  let failed = |error: std::io::Error| error.to_string();
+  let mut writer = BufWriter::new(File::create(migrated_file).map_err(failed)?);
+  writer.write_all(&format.header()).map_err(failed)?;
+  while let Some(mut record) = reader.next_record()? {
+    record.seq += numbered;
+    writer.write_all(&format.encode(&record)).map_err(failed)?;
+  }
+  if let Some((offset, entry, reason)) = reader.corrupt.first() {
+    return Err(format!(
+      "unreadable record at entry {} (byte {}): {}. Repair the topic first.",
+      entry, offset, reason
+    ));
+  }
+  if reader.has_damaged_tail() {
+    return Err(format!(
+      "damaged record at byte {}. Repair the topic first.",
+      reader.valid_len
+    ));
+  }
+  let file = writer.into_inner().map_err(|error| error.to_string())?;
+  file.sync_all().map_err(failed)?;
+  Ok(Some((version, reader.count)))
+}
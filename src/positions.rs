@@ -0,0 +1,136 @@
+//! Positions of records in a topic's list.
+//!
+//! A position is a sequence of numbers compared element by element, written
+//! with the numbers separated by dots (`3`, `2.1`, `2.0.4`). A record added
+//! with `ADD` is positioned by its sequence number, so records are listed in
+//! the order they were added. `INSERT` and `MOVE` store the position they
+//! chose in their record, picked to fall between the records on either
+//! side. There is always a position between two others, so no record ever
+//! has to be renumbered to make room. Positions never end in 0, which
+//! leaves room before every one of them.
+
+use std::fmt;
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Position(Vec<u64>);
+
+impl Position {
+  /// Position of a record added at the end of the list by `ADD`.
+  pub fn added(seq: u64) -> Position {
+    Position(vec![seq])
+  }
+
+  pub fn parse(text: &str) -> Result<Position, String> {
+    let parts: Result<Vec<u64>, _> = text.split('.').map(|part| part.parse::<u64>()).collect();
+    match parts {
+      Ok(parts) if parts.last().is_some_and(|last| *last > 0) => Ok(Position(parts)),
+      _ => Err(format!("invalid position \"{}\"", text)),
+    }
+  }
+
+  /// A position after `before` and before `after`, where either may be
+  /// missing at the ends of the list. `before` must come before `after`.
+  pub fn between(before: Option<&Position>, after: Option<&Position>) -> Position {
+    let empty: &[u64] = &[];
+    let before = before.map_or(empty, |position| &position.0);
+    Position(between(before, after.map(|position| position.0.as_slice())))
+  }
+}
+
+/// Digits strictly between `before` and `after` (unbounded when `None`),
+/// ending in a digit other than 0.
+fn between(before: &[u64], after: Option<&[u64]>) -> Vec<u64> {
+  let low = before.first().copied().unwrap_or(0);
+  let rest = before.get(1..).unwrap_or(&[]);
+  let high = match after.and_then(|after| after.first().map(|high| (*high, &after[1..]))) {
+    None => return vec![low + 1],
+    Some(high) => high,
+  };
+  let mut digits = vec![low];
+  match high.0 - low {
+    0 => digits.extend(between(rest, Some(high.1))),
+    1 => digits.extend(between(rest, None)),
+    _ => digits[0] = low + 1,
+  }
+  digits
+}
+
+impl fmt::Display for Position {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let parts: Vec<String> = self.0.iter().map(|part| part.to_string()).collect();
+    write!(f, "{}", parts.join("."))
+  }
+}
+
+/// Splits an `INSERT` record's content into the position it was inserted at
+/// and the content itself.
+pub fn split_insert(content: &str) -> Result<(Position, &str), String> {
+  let (position, content) = content.split_once(' ').unwrap_or((content, ""));
+  Ok((Position::parse(position)?, content))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn position(text: &str) -> Position {
+    Position::parse(text).expect("valid position")
+  }
+
+  /// The position between two others, checked to fall strictly between
+  /// them and to be a valid position itself.
+  fn between(before: Option<&str>, after: Option<&str>) -> String {
+    let before = before.map(position);
+    let after = after.map(position);
+    let found = Position::between(before.as_ref(), after.as_ref());
+    assert!(before.as_ref().is_none_or(|before| *before < found));
+    assert!(after.as_ref().is_none_or(|after| found < *after));
+    assert_eq!(Position::parse(&found.to_string()), Ok(found.clone()));
+    found.to_string()
+  }
+
+  #[test]
+  fn between_at_the_ends() {
+    assert_eq!(between(None, None), "1");
+    assert_eq!(between(Some("3"), None), "4");
+    assert_eq!(between(Some("2.5"), None), "3");
+    assert_eq!(between(None, Some("1")), "0.1");
+    assert_eq!(between(None, Some("3")), "1");
+    assert_eq!(between(None, Some("0.1")), "0.0.1");
+  }
+
+  #[test]
+  fn between_adjacent_positions() {
+    assert_eq!(between(Some("1"), Some("2")), "1.1");
+    assert_eq!(between(Some("1"), Some("3")), "2");
+    assert_eq!(between(Some("1"), Some("1.1")), "1.0.1");
+    assert_eq!(between(Some("1.1"), Some("2")), "1.2");
+    assert_eq!(between(Some("1.9"), Some("1.10")), "1.9.1");
+  }
+
+  #[test]
+  fn repeated_inserts_keep_finding_room() {
+    let mut after = position("2");
+    for _ in 0..50 {
+      let found = between(Some("1"), Some(&after.to_string()));
+      after = position(&found);
+    }
+  }
+
+  #[test]
+  fn parse_rejects_invalid_positions() {
+    for text in ["", "0", "1.0", "a", "1..2", "-1", "1."] {
+      assert!(Position::parse(text).is_err(), "{} was accepted", text);
+    }
+  }
+
+  #[test]
+  fn split_insert_separates_position_and_content() {
+    assert_eq!(
+      split_insert("1.1 two words"),
+      Ok((position("1.1"), "two words"))
+    );
+    assert_eq!(split_insert("3"), Ok((position("3"), "")));
+    assert!(split_insert("x content").is_err());
+  }
+}
@@ -0,0 +1,329 @@
+//! Options of the `LIST` command:
+//!
+//! ```text
+//! LIST [DETAILS]
+//!      [WHERE content LIKE|REGEX <pattern>]
+//!      [ORDER BY content|created|updated [ASC|DESC]]
+//!      [LIMIT <n>] [OFFSET <m>]
+//! ```
+//!
+//! Keywords are not case sensitive and the clauses come in the order shown.
+//! A pattern holding spaces, or a word that would be read as a keyword, is
+//! quoted with `"` or `'`; inside quotes a backslash escapes the next
+//! character. `LIKE` matches the whole content, with `%` standing for any
+//! run of characters and `_` for any one character. `REGEX` matches
+//! anywhere in the content unless anchored. `created` orders by when the
+//! record was added and `updated` by when its content was last written.
+//! Records that sort the same stay in list order.
+
+use regex::Regex;
+
+/// What records are ordered by.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SortKey {
+  Content,
+  Created,
+  Updated,
+}
+
+pub struct ListQuery {
+  /// Prefix each record with when it was written and its sequence number.
+  pub details: bool,
+  /// Only records whose content matches.
+  pub filter: Option<Regex>,
+  /// Order to list records in, and whether it is descending, instead of
+  /// list order.
+  pub order: Option<(SortKey, bool)>,
+  pub limit: Option<usize>,
+  pub offset: usize,
+}
+
+/// A word of the command, and whether it was quoted. Quoted words are never
+/// keywords.
+struct Token {
+  text: String,
+  quoted: bool,
+}
+
+impl Token {
+  fn is(&self, keyword: &str) -> bool {
+    !self.quoted && self.text.eq_ignore_ascii_case(keyword)
+  }
+}
+
+impl ListQuery {
+  /// Parses the text after `LIST`.
+  pub fn parse(text: &str) -> Result<ListQuery, String> {
+    let tokens = tokenize(text)?;
+    let mut rest = tokens.as_slice();
+    let mut query = ListQuery {
+      details: false,
+      filter: None,
+      order: None,
+      limit: None,
+      offset: 0,
+    };
+    if take_keyword(&mut rest, "DETAILS") {
+      query.details = true;
+    }
+    if take_keyword(&mut rest, "WHERE") {
+      match rest.first() {
+        Some(field) if field.is("CONTENT") => rest = &rest[1..],
+        Some(field) => return Err(format!("Unknown field {}. (expected content)", field.text)),
+        None => return Err("WHERE requires content".to_string()),
+      }
+      let regex = if take_keyword(&mut rest, "LIKE") {
+        like_pattern(&take_value(&mut rest, "LIKE")?)
+      } else if take_keyword(&mut rest, "REGEX") {
+        take_value(&mut rest, "REGEX")?
+      } else {
+        return Err("WHERE content requires LIKE or REGEX".to_string());
+      };
+      let filter = Regex::new(&regex).map_err(|error| format!("Invalid pattern: {}", error))?;
+      query.filter = Some(filter);
+    }
+    if take_keyword(&mut rest, "ORDER") {
+      if !take_keyword(&mut rest, "BY") {
+        return Err("ORDER requires BY".to_string());
+      }
+      let key = match rest.first() {
+        Some(key) if key.is("CONTENT") => SortKey::Content,
+        Some(key) if key.is("CREATED") => SortKey::Created,
+        Some(key) if key.is("UPDATED") => SortKey::Updated,
+        Some(key) => {
+          return Err(format!(
+            "Unknown sort key {}. (expected content, created or updated)",
+            key.text
+          ))
+        }
+        None => return Err("ORDER BY requires content, created or updated".to_string()),
+      };
+      rest = &rest[1..];
+      let descending = if take_keyword(&mut rest, "DESC") {
+        true
+      } else {
+        take_keyword(&mut rest, "ASC");
+        false
+      };
+      query.order = Some((key, descending));
+    }
+    if take_keyword(&mut rest, "LIMIT") {
+      query.limit = Some(take_count(&mut rest, "LIMIT")?);
+    }
+    if take_keyword(&mut rest, "OFFSET") {
+      query.offset = take_count(&mut rest, "OFFSET")?;
+    }
+    match rest.first() {
+      Some(token) => Err(format!("Unexpected {} in LIST", token.text)),
+      None => Ok(query),
+    }
+  }
+}
+
+/// Splits the text into words separated by whitespace, keeping quoted
+/// words whole.
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+  let mut tokens: Vec<Token> = Vec::new();
+  let mut chars = text.chars().peekable();
+  while let Some(c) = chars.next() {
+    if c.is_whitespace() {
+      continue;
+    }
+    if c == '"' || c == '\'' {
+      let mut word = String::new();
+      loop {
+        match chars.next() {
+          Some(next) if next == c => break,
+          Some('\\') => match chars.next() {
+            Some(escaped) => word.push(escaped),
+            None => return Err("Unterminated quote in LIST".to_string()),
+          },
+          Some(next) => word.push(next),
+          None => return Err("Unterminated quote in LIST".to_string()),
+        }
+      }
+      tokens.push(Token {
+        text: word,
+        quoted: true,
+      });
+    } else {
+      let mut word = c.to_string();
+      while let Some(next) = chars.next_if(|next| !next.is_whitespace()) {
+        word.push(next);
+      }
+      tokens.push(Token {
+        text: word,
+        quoted: false,
+      });
+    }
+  }
+  Ok(tokens)
+}
+
+/// Skips the keyword if it is the next token.
+fn take_keyword(rest: &mut &[Token], keyword: &str) -> bool {
+  match rest.first() {
+    Some(token) if token.is(keyword) => {
+      *rest = &rest[1..];
+      true
+    }
+    _ => false,
+  }
+}
+
+fn take_value(rest: &mut &[Token], keyword: &str) -> Result<String, String> {
+  match rest.first() {
+    Some(token) => {
+      *rest = &rest[1..];
+      Ok(token.text.to_string())
+    }
+    None => Err(format!("{} requires a pattern", keyword)),
+  }
+}
+
+fn take_count(rest: &mut &[Token], keyword: &str) -> Result<usize, String> {
+  match rest.first() {
+    Some(token) => {
+      *rest = &rest[1..];
+      token.text.parse::<usize>().map_err(|_| {
+        format!(
+          "Invalid {} {}. (expected a whole number)",
+          keyword, token.text
+        )
+      })
+    }
+    None => Err(format!("{} requires a number", keyword)),
+  }
+}
+
+/// Regular expression matching the same content as a `LIKE` pattern.
+fn like_pattern(pattern: &str) -> String {
+  let mut expression = String::from("^(?s)");
+  for c in pattern.chars() {
+    match c {
+      '%' => expression.push_str(".*"),
+      '_' => expression.push('.'),
+      _ => expression.push_str(&regex::escape(&c.to_string())),
+    }
+  }
+  expression.push('$');
+  expression
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(text: &str) -> ListQuery {
+    match ListQuery::parse(text) {
+      Ok(query) => query,
+      Err(message) => panic!("{:?} was rejected: {}", text, message),
+    }
+  }
+
+  fn error(text: &str) -> String {
+    match ListQuery::parse(text) {
+      Ok(_) => panic!("{:?} was accepted", text),
+      Err(message) => message,
+    }
+  }
+
+  fn matches(query: &ListQuery, content: &str) -> bool {
+    query
+      .filter
+      .as_ref()
+      .expect("query has a filter")
+      .is_match(content)
+  }
+
+  #[test]
+  fn empty_query_lists_everything() {
+    let query = parse("  ");
+    assert!(!query.details);
+    assert!(query.filter.is_none());
+    assert_eq!(query.order, None);
+    assert_eq!(query.limit, None);
+    assert_eq!(query.offset, 0);
+  }
+
+  #[test]
+  fn every_clause() {
+    let query = parse("details where CONTENT like 'a%' order by updated desc limit 5 offset 10");
+    assert!(query.details);
+    assert!(matches(&query, "apple"));
+    assert!(!matches(&query, "banana"));
+    assert_eq!(query.order, Some((SortKey::Updated, true)));
+    assert_eq!(query.limit, Some(5));
+    assert_eq!(query.offset, 10);
+    assert_eq!(
+      parse("ORDER BY created ASC").order,
+      Some((SortKey::Created, false))
+    );
+    assert_eq!(
+      parse("ORDER BY content").order,
+      Some((SortKey::Content, false))
+    );
+  }
+
+  #[test]
+  fn quoted_patterns() {
+    let query = parse("WHERE content LIKE \"two words\"");
+    assert!(matches(&query, "two words"));
+    assert!(!matches(&query, "two words more"));
+    let query = parse("WHERE content LIKE 'it\\'s _'");
+    assert!(matches(&query, "it's 1"));
+    let query = parse("WHERE content LIKE 'limit'");
+    assert!(matches(&query, "limit"));
+    assert_eq!(query.limit, None);
+    let query = parse("WHERE content LIKE \"\"");
+    assert!(matches(&query, ""));
+    assert!(!matches(&query, "a"));
+  }
+
+  #[test]
+  fn like_matches_literally_apart_from_wildcards() {
+    let query = parse("WHERE content LIKE a.c%");
+    assert!(matches(&query, "a.cde"));
+    assert!(!matches(&query, "abcde"));
+    assert!(matches(&parse("WHERE content LIKE %b%"), "a\nb\nc"));
+  }
+
+  #[test]
+  fn regex_matches_anywhere() {
+    let query = parse("WHERE content REGEX '[0-9]+'");
+    assert!(matches(&query, "abc 123"));
+    assert!(!matches(&query, "abc"));
+  }
+
+  #[test]
+  fn errors() {
+    let cases = [
+      ("WHERE", "WHERE requires content"),
+      (
+        "WHERE name LIKE a",
+        "Unknown field name. (expected content)",
+      ),
+      ("WHERE content", "WHERE content requires LIKE or REGEX"),
+      ("WHERE content = a", "WHERE content requires LIKE or REGEX"),
+      ("WHERE content LIKE", "LIKE requires a pattern"),
+      ("WHERE content REGEX", "REGEX requires a pattern"),
+      ("ORDER content", "ORDER requires BY"),
+      ("ORDER BY", "ORDER BY requires content, created or updated"),
+      (
+        "ORDER BY size",
+        "Unknown sort key size. (expected content, created or updated)",
+      ),
+      ("LIMIT", "LIMIT requires a number"),
+      ("LIMIT -1", "Invalid LIMIT -1. (expected a whole number)"),
+      ("OFFSET x", "Invalid OFFSET x. (expected a whole number)"),
+      ("LIMIT 1 LIMIT 2", "Unexpected LIMIT in LIST"),
+      ("OFFSET 1 LIMIT 2", "Unexpected LIMIT in LIST"),
+      ("WHERE content LIKE 'open", "Unterminated quote in LIST"),
+      ("WHERE content LIKE 'open\\", "Unterminated quote in LIST"),
+    ];
+    for (text, message) in cases {
+      assert_eq!(error(text), message, "for {:?}", text);
+    }
+    assert!(error("WHERE content REGEX '('").starts_with("Invalid pattern: "));
+  }
+}
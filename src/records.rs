@@ -0,0 +1,773 @@
+//! On-disk layout of topic files.
+//!
+//! A topic file starts with a header line naming the format version,
+//! followed by one record per line. Each record line is prefixed with the
+//! CRC-32 of its payload so a record that was only partly written (or was
+//! damaged later) can be told apart from a good one:
+//!
+//! ```text
+//! LISTDB-TOPIC 2
+//! <crc32 as 8 hex digits><payload>
+//! ```
+//!
+//! Topics created as `FRAMED` declare so in the header and store each
+//! record length-prefixed instead, so content may contain line breaks or
+//! any other character:
+//!
+//! ```text
+//! LISTDB-TOPIC 2 FRAMED
+//! <payload length: u32 LE><crc32: u32 LE><payload>
+//! ```
+//!
+//! Topics created as `JSON` hold a JSON object as the content of each
+//! record, and end their header with `JSON` (`LISTDB-TOPIC 2 JSON`,
+//! `LISTDB-TOPIC 2 FRAMED JSON`).
+//!
+//! From version 2 the payload carries the record's sequence number and the
+//! time it was written (milliseconds since the epoch, UTC):
+//!
+//! ```text
+//! <sequence> <timestamp> <id> <action> <content>
+//! ```
+//!
+//! and ids may be of any length, so a record can have a key chosen by the
+//! caller in place of a generated UUID.
+//!
+//! A timestamp of 0 means the time the record was written is not known, as
+//! for records migrated from an older version.
+//!
+//! A version 2 header may end with `SEQ <n>`, the highest sequence number
+//! handed out before the file's first record. Compaction records it when it
+//! drops the records that held the highest numbers, so numbers are not
+//! handed out again.
+//!
+//! Version 1 payloads are `<uuid><action><content>`; their records are
+//! numbered in the order they are read and have no timestamp. Files written
+//! before the header was introduced (version 0) have no header and no
+//! checksums. Every version is listed in `FORMAT_VERSIONS`. Older files are
+//! still read and appended to as-is until they are migrated.
+
+use crate::documents;
+use chrono::prelude::*;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+pub const ACTION_ADD: &str = "A";
+pub const ACTION_DELETE: &str = "D";
+pub const ACTION_UPDATE: &str = "U";
+/// Adds a record at a position in the list. The content is the position
+/// followed by a space and the record's content.
+pub const ACTION_INSERT: &str = "I";
+/// Moves a record to the position given as the content.
+pub const ACTION_MOVE: &str = "M";
+
+const HEADER_MAGIC: &str = "LISTDB-TOPIC";
+const ID_LENGTH: usize = 36;
+/// Longest key a caller can give a record.
+pub const MAX_KEY_LENGTH: usize = 128;
+const CRC_LENGTH: usize = 8;
+const FRAME_HEADER_LENGTH: usize = 8;
+
+pub const LEGACY_VERSION: u32 = 0;
+pub const CURRENT_VERSION: u32 = 2;
+
+/// A version of the topic file format.
+pub struct FormatVersion {
+  pub version: u32,
+  /// What the version changed.
+  pub description: &'static str,
+}
+
+/// Every version of the topic file format, oldest first. Files in any of
+/// these versions can be read, and migrated to `CURRENT_VERSION`.
+pub const FORMAT_VERSIONS: [FormatVersion; 3] = [
+  FormatVersion {
+    version: LEGACY_VERSION,
+    description: "one record per line, no header or checksums",
+  },
+  FormatVersion {
+    version: 1,
+    description: "format header and checksummed records",
+  },
+  FormatVersion {
+    version: CURRENT_VERSION,
+    description: "sequence numbers and timestamps",
+  },
+];
+
+impl FormatVersion {
+  pub fn find(version: u32) -> Option<&'static FormatVersion> {
+    FORMAT_VERSIONS
+      .iter()
+      .find(|format_version| format_version.version == version)
+  }
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+  let mut table = [0u32; 256];
+  let mut n = 0;
+  while n < 256 {
+    let mut c = n as u32;
+    let mut k = 0;
+    while k < 8 {
+      c = if c & 1 != 0 {
+        0xEDB8_8320 ^ (c >> 1)
+      } else {
+        c >> 1
+      };
+      k += 1;
+    }
+    table[n] = c;
+    n += 1;
+  }
+  table
+}
+
+/// CRC-32 (IEEE) of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = 0xFFFF_FFFFu32;
+  for byte in bytes {
+    crc = CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+  }
+  crc ^ 0xFFFF_FFFF
+}
+
+/// Returns why `key` cannot be used as a record's id, if it cannot. Ids are
+/// generated UUIDs or keys chosen with `PUT`: 1 to `MAX_KEY_LENGTH` ASCII
+/// letters, digits, `-`, `_`, `.` or `:`.
+pub fn check_key(key: &str) -> Result<(), String> {
+  let valid_character =
+    |character: char| character.is_ascii_alphanumeric() || "-_.:".contains(character);
+  if key.is_empty() || key.len() > MAX_KEY_LENGTH || !key.chars().all(valid_character) {
+    return Err(format!(
+      "Invalid key \"{}\". (expected 1 to {} letters, digits, '-', '_', '.' or ':')",
+      key, MAX_KEY_LENGTH
+    ));
+  }
+  Ok(())
+}
+
+#[derive(Clone)]
+pub struct Record {
+  pub id: String,
+  pub action: String,
+  pub content: String,
+  pub seq: u64,
+  pub timestamp: Option<DateTime<Utc>>,
+}
+
+/// How records are delimited within a topic file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+  /// One record per line. Content cannot contain a line break.
+  Line,
+  /// Each record is prefixed with its length.
+  Framed,
+}
+
+impl Encoding {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Encoding::Line => "LINE",
+      Encoding::Framed => "FRAMED",
+    }
+  }
+}
+
+/// What the content of a topic's records holds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Content {
+  /// Any text.
+  Text,
+  /// A JSON object.
+  Json,
+}
+
+impl Content {
+  pub fn name(&self) -> &'static str {
+    match self {
+      Content::Text => "TEXT",
+      Content::Json => "JSON",
+    }
+  }
+}
+
+/// Format of a topic file, as declared by its header.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Format {
+  pub version: u32,
+  pub encoding: Encoding,
+  pub content: Content,
+  /// Highest sequence number handed out before the file's records, or 0
+  /// when the header does not record it.
+  pub prior_seq: u64,
+}
+
+impl Format {
+  /// Format used for newly created topic files.
+  pub fn current(encoding: Encoding) -> Format {
+    Format {
+      version: CURRENT_VERSION,
+      encoding,
+      content: Content::Text,
+      prior_seq: 0,
+    }
+  }
+
+  /// True when files in this format need no migration.
+  pub fn is_current(&self) -> bool {
+    self.version == CURRENT_VERSION
+  }
+
+  /// The current format with the same encoding, which a file in this format
+  /// is migrated to.
+  pub fn upgraded(&self) -> Format {
+    Format {
+      content: self.content,
+      prior_seq: self.prior_seq,
+      ..Format::current(self.encoding)
+    }
+  }
+
+  /// Header written at the start of a file in this format.
+  pub fn header(&self) -> Vec<u8> {
+    if self.version == LEGACY_VERSION {
+      return Vec::new();
+    }
+    let mut header = format!("{} {}", HEADER_MAGIC, self.version);
+    if self.encoding != Encoding::Line {
+      header = format!("{} {}", header, self.encoding.name());
+    }
+    if self.content != Content::Text {
+      header = format!("{} {}", header, self.content.name());
+    }
+    if self.prior_seq > 0 {
+      header = format!("{} SEQ {}", header, self.prior_seq);
+    }
+    format!("{}\n", header).into_bytes()
+  }
+
+  /// True when records in this format store their sequence number and
+  /// timestamp.
+  pub fn has_stamps(&self) -> bool {
+    self.version >= 2
+  }
+
+  /// Returns a reason the content cannot be stored in this format, if any.
+  pub fn reject_content(&self, content: &str) -> Option<String> {
+    if self.encoding == Encoding::Line && content.contains('\n') {
+      return Some("Content with line breaks requires a FRAMED topic.".to_string());
+    }
+    if self.content == Content::Json {
+      return documents::parse(content).err();
+    }
+    None
+  }
+
+  /// Reads the format from the start of a topic file, returning it along
+  /// with the length of the header.
+  pub fn read(contents: &[u8]) -> Result<(Format, usize), String> {
+    if !contents.starts_with(HEADER_MAGIC.as_bytes()) {
+      return Ok((
+        Format {
+          version: LEGACY_VERSION,
+          encoding: Encoding::Line,
+          content: Content::Text,
+          prior_seq: 0,
+        },
+        0,
+      ));
+    }
+    let header_end = match contents.iter().position(|byte| *byte == b'\n') {
+      Some(position) => position,
+      None => return Err("incomplete topic header".to_string()),
+    };
+    let header = String::from_utf8_lossy(&contents[..header_end]);
+    let tokens: Vec<&str> = header[HEADER_MAGIC.len()..].split_whitespace().collect();
+    let version = tokens
+      .first()
+      .and_then(|token| token.parse::<u32>().ok())
+      .ok_or_else(|| format!("invalid topic header \"{}\"", header))?;
+    if version == LEGACY_VERSION || FormatVersion::find(version).is_none() {
+      return Err(format!("unsupported topic format version {}", version));
+    }
+    let mut format = Format {
+      version,
+      encoding: Encoding::Line,
+      content: Content::Text,
+      prior_seq: 0,
+    };
+    let mut options = tokens[1..].iter();
+    while let Some(token) = options.next() {
+      match *token {
+        "FRAMED" => format.encoding = Encoding::Framed,
+        "JSON" => format.content = Content::Json,
+        "SEQ" if format.has_stamps() => {
+          format.prior_seq = options
+            .next()
+            .and_then(|seq| seq.parse::<u64>().ok())
+            .ok_or_else(|| format!("invalid topic header \"{}\"", header))?;
+        }
+        other => return Err(format!("unsupported topic encoding {}", other)),
+      }
+    }
+    Ok((format, header_end + 1))
+  }
+
+  /// Encodes a record as it is appended to a file in this format.
+  pub fn encode(&self, record: &Record) -> Vec<u8> {
+    let payload = if self.has_stamps() {
+      let timestamp = record.timestamp.map_or(0, |time| time.timestamp_millis());
+      format!(
+        "{} {} {} {} {}",
+        record.seq, timestamp, record.id, record.action, record.content
+      )
+    } else {
+      format!("{}{}{}", record.id, record.action, record.content)
+    };
+    let payload = payload.into_bytes();
+    let mut output: Vec<u8> = Vec::with_capacity(payload.len() + FRAME_HEADER_LENGTH + 1);
+    if self.version == LEGACY_VERSION {
+      output.extend_from_slice(&payload);
+      output.push(b'\n');
+      return output;
+    }
+    match self.encoding {
+      Encoding::Line => {
+        output.extend_from_slice(format!("{:08x}", crc32(&payload)).as_bytes());
+        output.extend_from_slice(&payload);
+        output.push(b'\n');
+      }
+      Encoding::Framed => {
+        output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        output.extend_from_slice(&crc32(&payload).to_le_bytes());
+        output.extend_from_slice(&payload);
+      }
+    }
+    output
+  }
+
+  /// Decodes a single line (without its newline). Returns `Ok(None)` for
+  /// lines that carry no record.
+  fn decode_line(&self, line: &[u8]) -> Result<Option<Record>, String> {
+    let payload = if self.version == LEGACY_VERSION {
+      line
+    } else {
+      if line.len() < CRC_LENGTH {
+        return Err("record too short".to_string());
+      }
+      let (crc_text, payload) = line.split_at(CRC_LENGTH);
+      let expected = std::str::from_utf8(crc_text)
+        .ok()
+        .and_then(|text| u32::from_str_radix(text, 16).ok())
+        .ok_or_else(|| "invalid checksum".to_string())?;
+      if crc32(payload) != expected {
+        return Err("checksum mismatch".to_string());
+      }
+      payload
+    };
+    self.decode_payload(payload)
+  }
+
+  fn decode_payload(&self, payload: &[u8]) -> Result<Option<Record>, String> {
+    let payload = match std::str::from_utf8(payload) {
+      Ok(payload) => payload,
+      Err(_) => return Err("record is not valid UTF-8".to_string()),
+    };
+    if self.has_stamps() {
+      return Format::decode_stamped(payload).map(Some);
+    }
+    if payload.len() <= ID_LENGTH + 1 {
+      if self.version == LEGACY_VERSION {
+        return Ok(None);
+      }
+      return Err("record too short".to_string());
+    }
+    match (
+      payload.get(..ID_LENGTH),
+      payload.get(ID_LENGTH..ID_LENGTH + 1),
+      payload.get(ID_LENGTH + 1..),
+    ) {
+      (Some(id), Some(action), Some(content)) => Ok(Some(Record {
+        id: id.to_string(),
+        action: action.to_string(),
+        content: content.to_string(),
+        seq: 0,
+        timestamp: None,
+      })),
+      _ => Err("malformed record".to_string()),
+    }
+  }
+
+  fn decode_stamped(payload: &str) -> Result<Record, String> {
+    let fields: Vec<&str> = payload.splitn(5, ' ').collect();
+    if fields.len() < 5 {
+      return Err("record too short".to_string());
+    }
+    let seq = fields[0]
+      .parse::<u64>()
+      .map_err(|_| "invalid sequence number".to_string())?;
+    let timestamp = match fields[1].parse::<i64>() {
+      Ok(0) => None,
+      Ok(millis) => Some(
+        Utc
+          .timestamp_millis_opt(millis)
+          .single()
+          .ok_or_else(|| "invalid timestamp".to_string())?,
+      ),
+      Err(_) => return Err("invalid timestamp".to_string()),
+    };
+    if fields[2].is_empty() || fields[3].len() != 1 {
+      return Err("malformed record".to_string());
+    }
+    Ok(Record {
+      id: fields[2].to_string(),
+      action: fields[3].to_string(),
+      content: fields[4].to_string(),
+      seq,
+      timestamp,
+    })
+  }
+}
+
+/// Reads the records of a topic file one at a time through a buffer, so
+/// the file is never held in memory as a whole. Records that cannot be read
+/// are skipped and noted in `corrupt`.
+pub struct LogReader<R> {
+  reader: R,
+  pub format: Format,
+  /// Byte offset, entry number and reason for each record that could not
+  /// be read.
+  pub corrupt: Vec<(usize, usize, String)>,
+  /// Length of the file up to the end of the last good record.
+  pub valid_len: usize,
+  /// Byte offset of the last good record.
+  pub last_offset: Option<usize>,
+  /// Sequence number of the last good record, as read from the file.
+  pub last_seq: Option<u64>,
+  /// Number of good records read.
+  pub count: u64,
+  /// Number of the line or frame last read. Lines are numbered as in a text
+  /// editor, counting the header; frames are numbered from the first after
+  /// the header. Numbering starts wherever reading started.
+  pub entry: usize,
+  /// Byte offset and entry number of an incomplete record at the end of the
+  /// file, such as a write torn by a crash.
+  pub incomplete: Option<(usize, usize)>,
+  /// Byte offset of the next unread byte.
+  offset: usize,
+  buffer: Vec<u8>,
+}
+
+impl LogReader<BufReader<File>> {
+  /// Opens a topic file and reads its header, skipping ahead to the record
+  /// at byte `start` if that is past the header. Records of formats without
+  /// sequence numbers are numbered from the first one read, so `start` is
+  /// only meaningful for formats that have them.
+  pub fn open(path: &Path, start: usize) -> Result<LogReader<BufReader<File>>, String> {
+    let file = File::open(path).map_err(|error| error.to_string())?;
+    let file_len = file.metadata().map_err(|error| error.to_string())?.len();
+    let mut log = LogReader::new(BufReader::new(file))?;
+    if start > log.offset {
+      if start as u64 > file_len {
+        return Err(format!("offset {} is past the end of the file", start));
+      }
+      log
+        .reader
+        .seek(SeekFrom::Start(start as u64))
+        .map_err(|error| error.to_string())?;
+      log.offset = start;
+      log.valid_len = start;
+    }
+    Ok(log)
+  }
+}
+
+impl<R: BufRead> LogReader<R> {
+  /// Reads the header at the start of `reader`.
+  pub fn new(mut reader: R) -> Result<LogReader<R>, String> {
+    let mut header: Vec<u8> = Vec::new();
+    let peek = reader.fill_buf().map_err(|error| error.to_string())?;
+    if peek.starts_with(HEADER_MAGIC.as_bytes()) {
+      reader
+        .read_until(b'\n', &mut header)
+        .map_err(|error| error.to_string())?;
+    }
+    let (format, header_len) = Format::read(&header)?;
+    let entry = match format.encoding {
+      Encoding::Line if header_len > 0 => 1,
+      _ => 0,
+    };
+    Ok(LogReader {
+      reader,
+      format,
+      corrupt: Vec::new(),
+      valid_len: header_len,
+      last_offset: None,
+      last_seq: None,
+      count: 0,
+      entry,
+      incomplete: None,
+      offset: header_len,
+      buffer: Vec::new(),
+    })
+  }
+
+  /// Reads the next good record, or `None` at the end of the file. Records
+  /// of formats without sequence numbers are numbered in the order read.
+  pub fn next_record(&mut self) -> Result<Option<Record>, String> {
+    loop {
+      let start = self.offset;
+      self.entry += 1;
+      let decoded = match self.format.encoding {
+        Encoding::Line => self.read_line(),
+        Encoding::Framed => self.read_frame(),
+      };
+      let decoded = match decoded.map_err(|error| error.to_string())? {
+        Some(decoded) => decoded,
+        None => {
+          if self.offset > start {
+            self.incomplete = Some((start, self.entry));
+          }
+          return Ok(None);
+        }
+      };
+      match decoded {
+        Ok(Some(mut record)) => {
+          self.count += 1;
+          if !self.format.has_stamps() {
+            record.seq = self.count;
+          }
+          self.valid_len = self.offset;
+          self.last_offset = Some(start);
+          self.last_seq = Some(record.seq);
+          return Ok(Some(record));
+        }
+        Ok(None) => self.valid_len = self.offset,
+        Err(reason) => self.corrupt.push((start, self.entry, reason)),
+      }
+    }
+  }
+
+  /// Number of bytes read so far. Once every record has been read this is
+  /// the length of the file.
+  pub fn file_len(&self) -> usize {
+    self.offset
+  }
+
+  /// True when the file ends in bytes that do not form a complete, valid
+  /// record, such as a write torn by a crash. Only meaningful once every
+  /// record has been read.
+  pub fn has_damaged_tail(&self) -> bool {
+    self.valid_len < self.offset
+  }
+
+  /// Reads and decodes the next line. Returns `None` at the end of the file
+  /// or when the last line is incomplete.
+  fn read_line(&mut self) -> io::Result<Option<Result<Option<Record>, String>>> {
+    self.buffer.clear();
+    let read = self.reader.read_until(b'\n', &mut self.buffer)?;
+    self.offset += read;
+    if self.buffer.last() != Some(&b'\n') {
+      return Ok(None);
+    }
+    let line = &self.buffer[..self.buffer.len() - 1];
+    Ok(Some(self.format.decode_line(line)))
+  }
+
+  /// Reads and decodes the next frame. A frame with a bad checksum is
+  /// skipped using its length, so a damaged record in the middle of a file
+  /// does not hide the records after it. If the length itself was damaged,
+  /// nothing after it will check out and the rest of the file is left as a
+  /// damaged tail. Returns `None` at the end of the file or when the last
+  /// frame is incomplete.
+  fn read_frame(&mut self) -> io::Result<Option<Result<Option<Record>, String>>> {
+    self.buffer.clear();
+    let read = (&mut self.reader)
+      .take(FRAME_HEADER_LENGTH as u64)
+      .read_to_end(&mut self.buffer)?;
+    self.offset += read;
+    if read < FRAME_HEADER_LENGTH {
+      return Ok(None);
+    }
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&self.buffer[0..4]);
+    let mut crc = [0u8; 4];
+    crc.copy_from_slice(&self.buffer[4..8]);
+    let length = u32::from_le_bytes(length) as usize;
+    self.buffer.clear();
+    let read = (&mut self.reader)
+      .take(length as u64)
+      .read_to_end(&mut self.buffer)?;
+    self.offset += read;
+    if read < length {
+      return Ok(None);
+    }
+    if crc32(&self.buffer) != u32::from_le_bytes(crc) {
+      return Ok(Some(Err("checksum mismatch".to_string())));
+    }
+    Ok(Some(self.format.decode_payload(&self.buffer)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  const ID: &str = "6f1c1f5e-2a4b-4c1d-9e8f-0a1b2c3d4e5f";
+
+  fn format(version: u32, encoding: Encoding) -> Format {
+    Format {
+      version,
+      encoding,
+      content: Content::Text,
+      prior_seq: 0,
+    }
+  }
+
+  fn record(seq: u64, content: &str) -> Record {
+    Record {
+      id: ID.to_string(),
+      action: ACTION_ADD.to_string(),
+      content: content.to_string(),
+      seq,
+      timestamp: Utc.timestamp_millis_opt(1_700_000_000_123).single(),
+    }
+  }
+
+  /// A file in `format` holding the records, and the length of each record
+  /// as written.
+  fn write(format: &Format, records: &[Record]) -> (Vec<u8>, Vec<usize>) {
+    let mut file = format.header();
+    let mut lengths: Vec<usize> = Vec::new();
+    for record in records {
+      let encoded = format.encode(record);
+      lengths.push(encoded.len());
+      file.extend_from_slice(&encoded);
+    }
+    (file, lengths)
+  }
+
+  fn read(file: Vec<u8>) -> (LogReader<Cursor<Vec<u8>>>, Vec<Record>) {
+    let mut reader = LogReader::new(Cursor::new(file)).expect("header is readable");
+    let mut records: Vec<Record> = Vec::new();
+    while let Some(record) = reader.next_record().expect("file is readable") {
+      records.push(record);
+    }
+    (reader, records)
+  }
+
+  fn contents(records: &[Record]) -> Vec<&str> {
+    records
+      .iter()
+      .map(|record| record.content.as_str())
+      .collect()
+  }
+
+  fn assert_round_trip(format: Format, content: &[&str]) {
+    let written: Vec<Record> = content
+      .iter()
+      .enumerate()
+      .map(|(index, content)| record(index as u64 + 1, content))
+      .collect();
+    let (file, _) = write(&format, &written);
+    let (reader, records) = read(file);
+    assert_eq!(reader.format, format);
+    assert_eq!(contents(&records), content);
+    assert!(reader.corrupt.is_empty());
+    assert!(!reader.has_damaged_tail());
+    for (index, record) in records.iter().enumerate() {
+      assert_eq!(record.id, ID);
+      assert_eq!(record.action, ACTION_ADD);
+      assert_eq!(record.seq, index as u64 + 1);
+      let expected = written[index].timestamp.filter(|_| format.has_stamps());
+      assert_eq!(record.timestamp, expected);
+    }
+  }
+
+  #[test]
+  fn line_records_round_trip() {
+    assert_round_trip(
+      format(CURRENT_VERSION, Encoding::Line),
+      &["one", "two words", ""],
+    );
+  }
+
+  #[test]
+  fn framed_records_round_trip_with_line_breaks() {
+    assert_round_trip(
+      format(CURRENT_VERSION, Encoding::Framed),
+      &["one", "two\nlines", "\r\n\n"],
+    );
+  }
+
+  #[test]
+  fn version_1_records_round_trip() {
+    assert_round_trip(format(1, Encoding::Line), &["one", "two words"]);
+    assert_round_trip(format(1, Encoding::Framed), &["one", "two\nlines"]);
+  }
+
+  #[test]
+  fn version_0_records_round_trip() {
+    let format = format(LEGACY_VERSION, Encoding::Line);
+    assert!(format.header().is_empty());
+    assert_round_trip(format, &["one", "two words"]);
+  }
+
+  #[test]
+  fn header_options_round_trip() {
+    let format = Format {
+      content: Content::Json,
+      prior_seq: 42,
+      ..format(CURRENT_VERSION, Encoding::Framed)
+    };
+    let header = format.header();
+    assert_eq!(header, b"LISTDB-TOPIC 2 FRAMED JSON SEQ 42\n");
+    assert_eq!(Format::read(&header), Ok((format, header.len())));
+  }
+
+  #[test]
+  fn header_errors() {
+    assert!(Format::read(b"LISTDB-TOPIC 2").is_err());
+    assert!(Format::read(b"LISTDB-TOPIC 9\n").is_err());
+    assert!(Format::read(b"LISTDB-TOPIC 2 ZIPPED\n").is_err());
+    assert!(Format::read(b"LISTDB-TOPIC 2 SEQ\n").is_err());
+    assert!(Format::read(b"LISTDB-TOPIC 1 SEQ 4\n").is_err());
+  }
+
+  #[test]
+  fn torn_tail_is_left_unread() {
+    for encoding in [Encoding::Line, Encoding::Framed] {
+      let format = format(CURRENT_VERSION, encoding);
+      let (mut file, lengths) = write(&format, &[record(1, "one"), record(2, "two")]);
+      let complete = file.len() - lengths[1];
+      file.truncate(file.len() - 2);
+      let (reader, records) = read(file);
+      assert_eq!(contents(&records), ["one"]);
+      assert!(reader.corrupt.is_empty());
+      assert!(reader.has_damaged_tail());
+      assert_eq!(reader.valid_len, complete);
+      assert_eq!(reader.incomplete.map(|(offset, _)| offset), Some(complete));
+    }
+  }
+
+  #[test]
+  fn corrupt_record_is_skipped() {
+    for encoding in [Encoding::Line, Encoding::Framed] {
+      let format = format(CURRENT_VERSION, encoding);
+      let written = [record(1, "one"), record(2, "two"), record(3, "three")];
+      let (mut file, lengths) = write(&format, &written);
+      let second = format.header().len() + lengths[0];
+      file[second + lengths[1] - 2] ^= 0x01;
+      let (reader, records) = read(file);
+      assert_eq!(contents(&records), ["one", "three"]);
+      assert_eq!(reader.corrupt.len(), 1);
+      assert_eq!(reader.corrupt[0].0, second);
+      assert!(!reader.has_damaged_tail());
+    }
+  }
+}
@@ -0,0 +1,231 @@
+//! Schemas of `JSON` topics.
+//!
+//! A schema lists fields the records of a topic must have the type of, and
+//! which of them every record must have. Fields of nested objects are named
+//! with dots, as in `FIND WHERE`. A field that is missing or `null` is
+//! accepted unless it is required, and fields the schema does not list are
+//! always accepted. The schema is checked as records are written; changing
+//! it leaves the records already in the log as they are.
+//!
+//! The schema is kept in a file of its own next to the topic's records, one
+//! field per line:
+//!
+//! ```text
+//! LISTDB-SCHEMA 1
+//! <field> <type> [REQUIRED]
+//! ```
+
+use crate::documents;
+use crate::storage;
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SCHEMA_MAGIC: &str = "LISTDB-SCHEMA";
+const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FieldType {
+  String,
+  Number,
+  Integer,
+  Boolean,
+  Array,
+  Object,
+}
+
+impl FieldType {
+  pub fn parse(name: &str) -> Result<FieldType, String> {
+    match name.to_uppercase().as_str() {
+      "STRING" => Ok(FieldType::String),
+      "NUMBER" => Ok(FieldType::Number),
+      "INTEGER" => Ok(FieldType::Integer),
+      "BOOLEAN" => Ok(FieldType::Boolean),
+      "ARRAY" => Ok(FieldType::Array),
+      "OBJECT" => Ok(FieldType::Object),
+      _ => Err(format!(
+        "Unknown field type {}. (expected STRING, NUMBER, INTEGER, BOOLEAN, ARRAY or OBJECT)",
+        name
+      )),
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      FieldType::String => "STRING",
+      FieldType::Number => "NUMBER",
+      FieldType::Integer => "INTEGER",
+      FieldType::Boolean => "BOOLEAN",
+      FieldType::Array => "ARRAY",
+      FieldType::Object => "OBJECT",
+    }
+  }
+
+  fn accepts(&self, value: &Value) -> bool {
+    match self {
+      FieldType::String => value.is_string(),
+      FieldType::Number => value.is_number(),
+      FieldType::Integer => value.is_i64() || value.is_u64(),
+      FieldType::Boolean => value.is_boolean(),
+      FieldType::Array => value.is_array(),
+      FieldType::Object => value.is_object(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct Field {
+  pub name: String,
+  pub field_type: FieldType,
+  pub required: bool,
+}
+
+impl Field {
+  /// Parses a field as given to `ALTER SCHEMA` and kept in the schema file:
+  /// a name, a type and optionally `REQUIRED`.
+  pub fn parse(tokens: &[&str]) -> Result<Field, String> {
+    let (name, field_type, required) = match tokens {
+      [name, field_type] => (name, field_type, false),
+      [name, field_type, flag] if flag.eq_ignore_ascii_case("REQUIRED") => (name, field_type, true),
+      _ => return Err("Expected a field name, a type and optionally REQUIRED.".to_string()),
+    };
+    Ok(Field {
+      name: name.to_string(),
+      field_type: FieldType::parse(field_type)?,
+      required,
+    })
+  }
+
+  /// The field's type, followed by `REQUIRED` if it is required.
+  pub fn describe(&self) -> String {
+    if self.required {
+      format!("{} REQUIRED", self.field_type.name())
+    } else {
+      self.field_type.name().to_string()
+    }
+  }
+}
+
+#[derive(Clone, Default)]
+pub struct Schema {
+  pub fields: Vec<Field>,
+}
+
+impl Schema {
+  /// Reads a schema file. A topic without one has an empty schema.
+  pub fn read(path: &Path) -> Result<Schema, String> {
+    let contents = match fs::read_to_string(path) {
+      Ok(contents) => contents,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Schema::default()),
+      Err(error) => return Err(error.to_string()),
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(&format!("{} {}", SCHEMA_MAGIC, SCHEMA_VERSION)) {
+      return Err("invalid schema header".to_string());
+    }
+    let mut fields: Vec<Field> = Vec::new();
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+      let tokens: Vec<&str> = line.split_whitespace().collect();
+      fields.push(Field::parse(&tokens)?);
+    }
+    Ok(Schema { fields })
+  }
+
+  /// Writes the schema to a temporary file and moves it into place. An
+  /// empty schema removes the file.
+  pub fn write(&self, path: &Path) -> io::Result<()> {
+    if self.fields.is_empty() {
+      return match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+      };
+    }
+    let mut contents = format!("{} {}\n", SCHEMA_MAGIC, SCHEMA_VERSION);
+    for field in &self.fields {
+      contents.push_str(&format!("{} {}\n", field.name, field.describe()));
+    }
+    storage::write_replacing(path, contents)
+  }
+
+  /// Returns the first field of a record's content the schema does not
+  /// accept, and why.
+  pub fn reject(&self, content: &str) -> Option<String> {
+    if self.fields.is_empty() {
+      return None;
+    }
+    let document = match documents::parse(content) {
+      Ok(document) => document,
+      Err(message) => return Some(message),
+    };
+    for field in &self.fields {
+      match documents::field(&document, &field.name) {
+        None | Some(Value::Null) if field.required => {
+          return Some(format!("Field {} is required.", field.name))
+        }
+        Some(value) if !value.is_null() && !field.field_type.accepts(value) => {
+          return Some(format!(
+            "Field {} must be of type {}.",
+            field.name,
+            field.field_type.name()
+          ))
+        }
+        _ => (),
+      }
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn schema(fields: &[&str]) -> Schema {
+    let fields = fields
+      .iter()
+      .map(|field| {
+        let tokens: Vec<&str> = field.split_whitespace().collect();
+        Field::parse(&tokens).expect("field parses")
+      })
+      .collect();
+    Schema { fields }
+  }
+
+  #[test]
+  fn reject_checks_required_fields_and_types() {
+    let schema = schema(&["name STRING REQUIRED", "age INTEGER", "address.city string"]);
+    assert_eq!(schema.reject(r#"{"name": "Ada", "age": 36}"#), None);
+    assert_eq!(schema.reject(r#"{"name": "Ada", "age": null}"#), None);
+    assert_eq!(schema.reject(r#"{"name": "Ada", "extra": [1]}"#), None);
+    assert_eq!(
+      schema.reject(r#"{"age": 36}"#),
+      Some("Field name is required.".to_string())
+    );
+    assert_eq!(
+      schema.reject(r#"{"name": null}"#),
+      Some("Field name is required.".to_string())
+    );
+    assert_eq!(
+      schema.reject(r#"{"name": "Ada", "age": 36.5}"#),
+      Some("Field age must be of type INTEGER.".to_string())
+    );
+    assert_eq!(
+      schema.reject(r#"{"name": "Ada", "address": {"city": 7}}"#),
+      Some("Field address.city must be of type STRING.".to_string())
+    );
+    assert!(schema.reject("not json").is_some());
+  }
+
+  #[test]
+  fn empty_schema_accepts_any_content() {
+    assert_eq!(Schema::default().reject("not json"), None);
+  }
+
+  #[test]
+  fn field_parse_rejects_unknown_types_and_flags() {
+    assert!(Field::parse(&["age", "DATE"]).is_err());
+    assert!(Field::parse(&["age", "INTEGER", "UNIQUE"]).is_err());
+    assert!(Field::parse(&["age"]).is_err());
+  }
+}
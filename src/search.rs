@@ -0,0 +1,293 @@
+//! Full-text search of the content of records.
+//!
+//! Content is split into terms: runs of letters and digits, lowercased. For
+//! `JSON` topics only the string and number values of a document are
+//! searched, not its field names. A search matches every record holding at
+//! least one of the terms searched for and ranks them with BM25, which
+//! favours records holding more of the terms, terms that are rare among the
+//! records searched and shorter records. A search across several topics
+//! ranks with the statistics of all of them together, so scores from
+//! different topics compare.
+//!
+//! A topic's search index is built from its records the first time it is
+//! searched and kept up to date as records are written after that. It is
+//! not kept on disk.
+
+use crate::documents;
+use crate::records::Content;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// How quickly repeating a term stops adding to a record's score.
+const K1: f64 = 1.2;
+/// How much a record's length counts against its score.
+const B: f64 = 0.75;
+
+/// A record found by a search.
+pub struct SearchHit {
+  /// Path of the topic the record is in, such as `\notes\daily`.
+  pub topic: String,
+  pub id: String,
+  pub content: String,
+  pub score: f64,
+  /// Number of terms in the record.
+  length: usize,
+  /// Number of times each term searched for appears in the record.
+  counts: HashMap<String, usize>,
+}
+
+/// Records found by a search, with the statistics of all the records
+/// searched that they are ranked by.
+#[derive(Default)]
+pub struct SearchResults {
+  /// Number of records searched.
+  records: usize,
+  /// Number of terms in all the records searched.
+  total_length: usize,
+  /// Number of records searched holding each term.
+  holding: HashMap<String, usize>,
+  pub hits: Vec<SearchHit>,
+}
+
+impl SearchResults {
+  /// Adds the results of searching another topic.
+  pub fn extend(&mut self, other: SearchResults) {
+    self.records += other.records;
+    self.total_length += other.total_length;
+    for (term, holding) in other.holding {
+      *self.holding.entry(term).or_insert(0) += holding;
+    }
+    self.hits.extend(other.hits);
+  }
+
+  /// Scores the hits and sorts them from the best match down, then by topic
+  /// and id.
+  pub fn rank(&mut self) {
+    let records = self.records as f64;
+    let average_length = self.total_length.max(1) as f64 / records.max(1.0);
+    for hit in self.hits.iter_mut() {
+      let mut score = 0.0;
+      for (term, count) in &hit.counts {
+        let holding = self.holding.get(term).copied().unwrap_or(0) as f64;
+        let rarity = (1.0 + (records - holding + 0.5) / (holding + 0.5)).ln();
+        let count = *count as f64;
+        let length = hit.length as f64;
+        score +=
+          rarity * count * (K1 + 1.0) / (count + K1 * (1.0 - B + B * length / average_length));
+      }
+      hit.score = score;
+    }
+    self.hits.sort_by(|a, b| {
+      b.score
+        .total_cmp(&a.score)
+        .then_with(|| (&a.topic, &a.id).cmp(&(&b.topic, &b.id)))
+    });
+  }
+}
+
+/// Splits text into the terms it is searched by.
+pub fn terms(text: &str) -> Vec<String> {
+  text
+    .split(|c: char| !c.is_alphanumeric())
+    .filter(|term| !term.is_empty())
+    .map(|term| term.to_lowercase())
+    .collect()
+}
+
+/// Inverted index of the terms in a topic's records.
+pub struct SearchIndex {
+  content: Content,
+  /// Number of times each term appears in each record, by term and id.
+  postings: HashMap<String, HashMap<String, usize>>,
+  /// Number of terms in each record.
+  lengths: HashMap<String, usize>,
+  total_length: usize,
+}
+
+impl SearchIndex {
+  pub fn new(content: Content) -> SearchIndex {
+    SearchIndex {
+      content,
+      postings: HashMap::new(),
+      lengths: HashMap::new(),
+      total_length: 0,
+    }
+  }
+
+  /// Terms of a record's content.
+  fn record_terms(&self, content: &str) -> Vec<String> {
+    match self.content {
+      Content::Text => terms(content),
+      Content::Json => {
+        let mut found: Vec<String> = Vec::new();
+        if let Ok(document) = documents::parse(content) {
+          value_terms(&document, &mut found);
+        }
+        found
+      }
+    }
+  }
+
+  pub fn insert(&mut self, id: &str, content: &str) {
+    let terms = self.record_terms(content);
+    self.total_length += terms.len();
+    self.lengths.insert(id.to_string(), terms.len());
+    for term in terms {
+      let counts = self.postings.entry(term).or_default();
+      *counts.entry(id.to_string()).or_insert(0) += 1;
+    }
+  }
+
+  pub fn remove(&mut self, id: &str, content: &str) {
+    if let Some(length) = self.lengths.remove(id) {
+      self.total_length -= length;
+    }
+    for term in self.record_terms(content) {
+      if let Some(counts) = self.postings.get_mut(&term) {
+        counts.remove(id);
+        if counts.is_empty() {
+          self.postings.remove(&term);
+        }
+      }
+    }
+  }
+
+  /// The records holding any of the terms, not yet ranked, with the
+  /// statistics of the topic's records. `content` gives the content of a
+  /// record by its id.
+  pub fn search<'a>(
+    &self,
+    topic: &str,
+    terms: &[String],
+    content: impl Fn(&str) -> Option<&'a str>,
+  ) -> SearchResults {
+    let mut results = SearchResults {
+      records: self.lengths.len(),
+      total_length: self.total_length,
+      ..SearchResults::default()
+    };
+    let mut found: HashMap<&str, HashMap<String, usize>> = HashMap::new();
+    let unique: HashSet<&String> = terms.iter().collect();
+    for term in unique {
+      let counts = match self.postings.get(term) {
+        Some(counts) => counts,
+        None => continue,
+      };
+      results.holding.insert(term.to_string(), counts.len());
+      for (id, count) in counts {
+        let counts = found.entry(id.as_str()).or_default();
+        counts.insert(term.to_string(), *count);
+      }
+    }
+    for (id, counts) in found {
+      if let Some(content) = content(id) {
+        results.hits.push(SearchHit {
+          topic: topic.to_string(),
+          id: id.to_string(),
+          content: content.to_string(),
+          score: 0.0,
+          length: self.lengths.get(id).copied().unwrap_or(0),
+          counts,
+        });
+      }
+    }
+    results
+  }
+}
+
+/// Adds the terms of the string and number values in a document.
+fn value_terms(value: &Value, found: &mut Vec<String>) {
+  match value {
+    Value::String(text) => found.extend(terms(text)),
+    Value::Number(number) => found.extend(terms(&number.to_string())),
+    Value::Array(items) => items.iter().for_each(|item| value_terms(item, found)),
+    Value::Object(fields) => fields.values().for_each(|field| value_terms(field, found)),
+    _ => (),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn index(content: Content, records: &[(&str, &str)]) -> SearchIndex {
+    let mut index = SearchIndex::new(content);
+    for (id, text) in records {
+      index.insert(id, text);
+    }
+    index
+  }
+
+  fn search(index: &SearchIndex, topic: &str, query: &str) -> SearchResults {
+    index.search(topic, &terms(query), |_| Some(""))
+  }
+
+  fn ranked(mut results: SearchResults) -> Vec<String> {
+    results.rank();
+    results.hits.iter().map(|hit| hit.id.clone()).collect()
+  }
+
+  #[test]
+  fn terms_are_lowercased_runs_of_letters_and_digits() {
+    assert_eq!(terms("Ship v2.0, ASAP!"), vec!["ship", "v2", "0", "asap"]);
+    assert!(terms(" -- ").is_empty());
+  }
+
+  #[test]
+  fn rank_favours_more_terms_rare_terms_and_shorter_records() {
+    let index = index(
+      Content::Text,
+      &[
+        ("both", "apple pie recipe"),
+        ("rare", "pie crust notes"),
+        ("short", "apple cake recipe"),
+        ("long", "apple tart recipe with cream and berries"),
+        ("none", "nothing to see"),
+        ("other", "more of nothing"),
+      ],
+    );
+    assert_eq!(
+      ranked(search(&index, "t", "apple pie")),
+      vec!["both", "rare", "short", "long"]
+    );
+  }
+
+  #[test]
+  fn equal_scores_are_ordered_by_topic_and_id() {
+    let first = index(Content::Text, &[("b", "pie"), ("a", "pie")]);
+    let second = index(Content::Text, &[("a", "pie")]);
+    let mut results = search(&second, "\\second", "pie");
+    results.extend(search(&first, "\\first", "pie"));
+    results.rank();
+    let hits: Vec<(&str, &str)> = results
+      .hits
+      .iter()
+      .map(|hit| (hit.topic.as_str(), hit.id.as_str()))
+      .collect();
+    assert_eq!(
+      hits,
+      vec![("\\first", "a"), ("\\first", "b"), ("\\second", "a")]
+    );
+  }
+
+  #[test]
+  fn json_records_are_searched_by_their_values_only() {
+    let index = index(
+      Content::Json,
+      &[
+        ("a", r#"{"title": "Pie", "serves": 4}"#),
+        ("b", r#"{"pie": "title", "tags": ["Tart"]}"#),
+      ],
+    );
+    assert_eq!(ranked(search(&index, "t", "pie")), vec!["a"]);
+    assert_eq!(ranked(search(&index, "t", "tart 4")), vec!["a", "b"]);
+  }
+
+  #[test]
+  fn removed_records_are_no_longer_found() {
+    let mut index = index(Content::Text, &[("a", "apple pie"), ("b", "apple tart")]);
+    index.remove("a", "apple pie");
+    assert_eq!(ranked(search(&index, "t", "apple pie")), vec!["b"]);
+    assert_eq!(index.total_length, 2);
+  }
+}
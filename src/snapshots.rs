@@ -0,0 +1,118 @@
+//! Snapshots of a topic's records.
+//!
+//! A snapshot holds the records of every live entry in a topic together
+//! with the point in the log it was taken at, so opening the topic only
+//! has to replay the records written after it. The file starts with a
+//! header line describing that point, followed by the records in the
+//! `FRAMED` topic format:
+//!
+//! ```text
+//! LISTDB-SNAPSHOT 1 <offset> <check seq> <last seq> <log records> <crc32> <file>
+//! LISTDB-TOPIC 2 FRAMED
+//! <records>
+//! ```
+//!
+//! `file` and `offset` give where to resume reading the log: the start of
+//! the last record covered by the snapshot, whose sequence number is
+//! `check seq`. Reading that record back confirms the log has not been
+//! rewritten since. When the file held no records yet, `offset` is the end
+//! of its header and `check seq` is `-`. The CRC-32 covers everything
+//! after the header line.
+
+use crate::records::{crc32, Encoding, Format, LogReader, Record};
+use crate::storage;
+use std::fs;
+use std::path::Path;
+
+const SNAPSHOT_MAGIC: &str = "LISTDB-SNAPSHOT";
+const SNAPSHOT_VERSION: u32 = 1;
+
+pub struct Snapshot {
+  /// Name of the topic file (or segment) to resume reading from.
+  pub file: String,
+  /// Byte offset in `file` to resume reading from.
+  pub offset: usize,
+  /// Sequence number of the record at `offset`, if there is one.
+  pub check_seq: Option<u64>,
+  pub last_seq: u64,
+  /// Number of records in the log up to and including the one at `offset`.
+  pub total_records: usize,
+  /// Records of the live entries.
+  pub records: Vec<Record>,
+}
+
+impl Snapshot {
+  /// Reads and checks a snapshot file.
+  pub fn read(path: &Path) -> Result<Snapshot, String> {
+    let contents = fs::read(path).map_err(|error| error.to_string())?;
+    let header_end = match contents.iter().position(|byte| *byte == b'\n') {
+      Some(position) => position,
+      None => return Err("missing header".to_string()),
+    };
+    let header = String::from_utf8_lossy(&contents[..header_end]);
+    let fields: Vec<&str> = header.splitn(8, ' ').collect();
+    if fields.len() != 8 || fields[0] != SNAPSHOT_MAGIC {
+      return Err("invalid header".to_string());
+    }
+    if fields[1] != SNAPSHOT_VERSION.to_string() {
+      return Err(format!("unsupported version {}", fields[1]));
+    }
+    let body = &contents[header_end + 1..];
+    if u32::from_str_radix(fields[6], 16) != Ok(crc32(body)) {
+      return Err("checksum mismatch".to_string());
+    }
+    let mut reader = LogReader::new(body)?;
+    let mut records: Vec<Record> = Vec::new();
+    while let Some(record) = reader.next_record()? {
+      records.push(record);
+    }
+    if !reader.corrupt.is_empty() || reader.has_damaged_tail() {
+      return Err("damaged records".to_string());
+    }
+    let number = |field: &str| {
+      field
+        .parse::<u64>()
+        .map_err(|_| "invalid header".to_string())
+    };
+    let check_seq = match fields[3] {
+      "-" => None,
+      seq => Some(number(seq)?),
+    };
+    Ok(Snapshot {
+      file: fields[7].to_string(),
+      offset: number(fields[2])? as usize,
+      check_seq,
+      last_seq: number(fields[4])?,
+      total_records: number(fields[5])? as usize,
+      records,
+    })
+  }
+
+  /// Writes the snapshot to a temporary file and moves it into place, so a
+  /// crash part way through leaves the previous snapshot intact.
+  pub fn write(&self, path: &Path) -> std::io::Result<()> {
+    let format = Format::current(Encoding::Framed);
+    let mut body = format.header();
+    for record in &self.records {
+      body.extend_from_slice(&format.encode(record));
+    }
+    let check_seq = match self.check_seq {
+      Some(seq) => seq.to_string(),
+      None => "-".to_string(),
+    };
+    let header = format!(
+      "{} {} {} {} {} {} {:08x} {}\n",
+      SNAPSHOT_MAGIC,
+      SNAPSHOT_VERSION,
+      self.offset,
+      check_seq,
+      self.last_seq,
+      self.total_records,
+      crc32(&body),
+      self.file
+    );
+    let mut contents = header.into_bytes();
+    contents.extend_from_slice(&body);
+    storage::write_replacing(path, contents)
+  }
+}
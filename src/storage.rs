@@ -0,0 +1,419 @@
+//! Files that hold a topic's records.
+//!
+//! A topic is stored either as a single `<topic>.tpc` file or, when created
+//! as `SEGMENTED`, as a `<topic>.tps` directory of numbered segment files
+//! (`00000001.seg`, `00000002.seg`, ...). Each segment has its own header
+//! and holds the records written while it was the last segment. A new
+//! segment is started once the last one reaches the configured size.
+//!
+//! A snapshot of the topic's records is kept as `<topic>.snap` next to a
+//! single file topic, or as `topic.snap` inside a segmented topic's
+//! directory. A topic's schema and the list of its indexes are kept the
+//! same way, as `<topic>.schema` and `<topic>.idx` or `topic.schema` and
+//! `topic.idx`.
+//!
+//! Compaction backups are kept next to the file they were taken from, named
+//! `<file>.bkp_<time stamp>`. All files backed up by one compaction share
+//! the same time stamp. A restore that brings back a segment which no longer
+//! exists leaves an empty `<segment>.bkp_<time stamp>.missing` marker in
+//! place of a backup of it, so that undoing the restore removes the segment
+//! again.
+
+use chrono::prelude::*;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const TOPIC_EXTENSION: &str = "tpc";
+pub const SEGMENTED_EXTENSION: &str = "tps";
+const SEGMENT_EXTENSION: &str = "seg";
+const SNAPSHOT_EXTENSION: &str = "snap";
+const SCHEMA_EXTENSION: &str = "schema";
+const INDEX_EXTENSION: &str = "idx";
+/// Extensions added to the name of a file for the temporary file written
+/// in its place before being renamed over it: by a whole-file write, a
+/// repair, a migration and a restore.
+const WRITE_TEMPORARY: &str = "tmp";
+pub const REPAIR_TEMPORARY: &str = "repair";
+pub const MIGRATE_TEMPORARY: &str = "migrate";
+pub const RESTORE_TEMPORARY: &str = "restore";
+/// Extension of the marker kept in place of the backup of a segment that
+/// did not exist.
+const MISSING_MARKER: &str = "missing";
+
+/// Format of the time stamp appended to the name of compaction backups.
+pub const BACKUP_STAMP: &str = "%Y%m%d_%H%M%S%f";
+
+#[derive(Clone)]
+pub enum Storage {
+  /// All records in one `<topic>.tpc` file.
+  Single(PathBuf),
+  /// Records split across the segment files of a `<topic>.tps` directory.
+  Segmented(PathBuf),
+}
+
+impl Storage {
+  /// Storage for a single file topic, where `base` is the topic's path
+  /// without an extension.
+  pub fn single(base: &str) -> Storage {
+    Storage::Single(PathBuf::from(format!("{}.{}", base, TOPIC_EXTENSION)))
+  }
+
+  /// Storage for a segmented topic, where `base` is the topic's path
+  /// without an extension.
+  pub fn segmented(base: &str) -> Storage {
+    Storage::Segmented(PathBuf::from(format!("{}.{}", base, SEGMENTED_EXTENSION)))
+  }
+
+  /// Finds the storage of an existing topic.
+  pub fn find(base: &str) -> Option<Storage> {
+    let single = Storage::single(base);
+    if single.exists() {
+      return Some(single);
+    }
+    let segmented = Storage::segmented(base);
+    if segmented.exists() {
+      return Some(segmented);
+    }
+    None
+  }
+
+  /// The topic file, or the directory holding the segments.
+  pub fn path(&self) -> &Path {
+    match self {
+      Storage::Single(path) => path,
+      Storage::Segmented(path) => path,
+    }
+  }
+
+  pub fn exists(&self) -> bool {
+    match self {
+      Storage::Single(path) => path.is_file(),
+      Storage::Segmented(path) => path.is_dir(),
+    }
+  }
+
+  pub fn is_segmented(&self) -> bool {
+    matches!(self, Storage::Segmented(_))
+  }
+
+  /// Creates the topic with a single empty file (or first segment) starting
+  /// with `header`.
+  pub fn create(&self, header: &[u8]) -> io::Result<()> {
+    match self {
+      Storage::Single(path) => fs::write(path, header),
+      Storage::Segmented(path) => {
+        fs::create_dir(path)?;
+        fs::write(path.join(segment_name(1)), header)
+      }
+    }
+  }
+
+  /// Removes the topic along with everything kept inside it.
+  pub fn remove(&self) -> io::Result<()> {
+    match self {
+      Storage::Single(path) => {
+        fs::remove_file(path)?;
+        for kept in [self.snapshot_path(), self.schema_path(), self.index_path()] {
+          match fs::remove_file(kept) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => (),
+          }
+        }
+        Ok(())
+      }
+      Storage::Segmented(path) => fs::remove_dir_all(path),
+    }
+  }
+
+  /// File the topic's snapshot is kept in.
+  pub fn snapshot_path(&self) -> PathBuf {
+    match self {
+      Storage::Single(path) => path.with_extension(SNAPSHOT_EXTENSION),
+      Storage::Segmented(path) => path.join(format!("topic.{}", SNAPSHOT_EXTENSION)),
+    }
+  }
+
+  /// Removes the topic's snapshot, if it has one, before its files are
+  /// rewritten or replaced.
+  pub fn remove_snapshot(&self) -> io::Result<()> {
+    match fs::remove_file(self.snapshot_path()) {
+      Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+      _ => Ok(()),
+    }
+  }
+
+  /// File the topic's schema is kept in.
+  pub fn schema_path(&self) -> PathBuf {
+    match self {
+      Storage::Single(path) => path.with_extension(SCHEMA_EXTENSION),
+      Storage::Segmented(path) => path.join(format!("topic.{}", SCHEMA_EXTENSION)),
+    }
+  }
+
+  /// File the topic's indexes are listed in.
+  pub fn index_path(&self) -> PathBuf {
+    match self {
+      Storage::Single(path) => path.with_extension(INDEX_EXTENSION),
+      Storage::Segmented(path) => path.join(format!("topic.{}", INDEX_EXTENSION)),
+    }
+  }
+
+  /// The files holding the topic's records, oldest first.
+  pub fn files(&self) -> io::Result<Vec<PathBuf>> {
+    match self {
+      Storage::Single(path) => Ok(vec![path.clone()]),
+      Storage::Segmented(path) => {
+        let mut segments: Vec<(u64, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(path)? {
+          let segment = entry?.path();
+          if let Some(number) = segment_number(&segment) {
+            segments.push((number, segment));
+          }
+        }
+        segments.sort_by_key(|(number, _)| *number);
+        Ok(segments.into_iter().map(|(_, segment)| segment).collect())
+      }
+    }
+  }
+
+  /// Total size in bytes of the topic's files.
+  pub fn size(&self) -> u64 {
+    self
+      .files()
+      .unwrap_or_default()
+      .iter()
+      .filter_map(|file| fs::metadata(file).ok())
+      .map(|metadata| metadata.len())
+      .sum()
+  }
+
+  /// Starts a new segment after `last` and returns its path.
+  pub fn roll(&self, last: &Path, header: &[u8]) -> io::Result<PathBuf> {
+    let directory = match self {
+      Storage::Single(_) => {
+        return Err(io::Error::other("single file topics do not have segments"))
+      }
+      Storage::Segmented(directory) => directory,
+    };
+    let number = segment_number(last).unwrap_or(0) + 1;
+    let segment = directory.join(segment_name(number));
+    fs::write(&segment, header)?;
+    Ok(segment)
+  }
+
+  /// Files inside a segmented topic's directory that are not segments,
+  /// segment backups, the topic's snapshot, its schema or its index file,
+  /// with the reason each is out of place.
+  pub fn stray_files(&self) -> io::Result<Vec<(PathBuf, String)>> {
+    let directory = match self {
+      Storage::Single(_) => return Ok(Vec::new()),
+      Storage::Segmented(directory) => directory,
+    };
+    let mut strays: Vec<(PathBuf, String)> = Vec::new();
+    for entry in fs::read_dir(directory)? {
+      let entry = entry?;
+      let path = entry.path();
+      let name = entry.file_name().to_string_lossy().to_string();
+      let reason = if entry.file_type()?.is_dir() {
+        "unexpected directory in a segmented topic"
+      } else if segment_number(&path).is_some()
+        || backup_stamp(&name).is_some()
+        || path == self.snapshot_path()
+        || path == self.schema_path()
+        || path == self.index_path()
+      {
+        continue;
+      } else if is_temporary(&name) {
+        "left over from an interrupted write"
+      } else {
+        "unknown file in a segmented topic"
+      };
+      strays.push((path, reason.to_string()));
+    }
+    Ok(strays)
+  }
+
+  /// Compaction backups with the time each was taken, newest first. Each
+  /// backup lists every file saved by that compaction.
+  pub fn backups(&self) -> Vec<(NaiveDateTime, Vec<PathBuf>)> {
+    let (directory, prefix) = match self {
+      Storage::Single(path) => match (path.parent(), path.file_name()) {
+        (Some(directory), Some(name)) => (directory, format!("{}.", name.to_string_lossy())),
+        _ => return Vec::new(),
+      },
+      Storage::Segmented(path) => (path.as_path(), String::new()),
+    };
+    let files = match fs::read_dir(directory) {
+      Ok(files) => files,
+      Err(_) => return Vec::new(),
+    };
+    let mut backups: Vec<(NaiveDateTime, Vec<PathBuf>)> = Vec::new();
+    for file in files.flatten() {
+      let name = file.file_name().to_string_lossy().to_string();
+      let stamp = match name.strip_prefix(&prefix).and_then(backup_stamp) {
+        Some(stamp) => stamp,
+        None => continue,
+      };
+      if let Ok(taken) = NaiveDateTime::parse_from_str(stamp, BACKUP_STAMP) {
+        match backups.iter_mut().find(|(time, _)| *time == taken) {
+          Some((_, paths)) => paths.push(file.path()),
+          None => backups.push((taken, vec![file.path()])),
+        }
+      }
+    }
+    backups.sort_by_key(|(taken, _)| std::cmp::Reverse(*taken));
+    backups
+  }
+}
+
+/// Path a file is backed up to by a compaction taken at `stamp`.
+pub fn backup_path(file: &Path, stamp: &str) -> PathBuf {
+  let mut name = file.as_os_str().to_os_string();
+  name.push(format!(".bkp_{}", stamp));
+  PathBuf::from(name)
+}
+
+/// Path of the marker recording that a segment did not exist when the
+/// backup taken at `stamp` was made.
+pub fn missing_marker_path(segment: &Path, stamp: &str) -> PathBuf {
+  let mut name = backup_path(segment, stamp).into_os_string();
+  name.push(format!(".{}", MISSING_MARKER));
+  PathBuf::from(name)
+}
+
+/// True when a backup is the marker of a segment that did not exist.
+pub fn is_missing_marker(backup: &Path) -> bool {
+  backup
+    .extension()
+    .is_some_and(|extension| extension == MISSING_MARKER)
+}
+
+/// Path of the temporary file written in place of `file` before being
+/// renamed over it, named with one of the temporary extensions.
+pub fn temporary_path(file: &Path, extension: &str) -> PathBuf {
+  let mut name = file.as_os_str().to_os_string();
+  name.push(format!(".{}", extension));
+  PathBuf::from(name)
+}
+
+/// Writes a whole file to a temporary file and renames it into place, so
+/// the file is never seen half written.
+pub fn write_replacing(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+  let temporary = temporary_path(path, WRITE_TEMPORARY);
+  fs::write(&temporary, contents)?;
+  fs::rename(&temporary, path)
+}
+
+/// Splits the name of a file kept next to a single file topic, such as its
+/// snapshot, into the topic's name and what the file is.
+fn kept_with_topic(name: &str) -> Option<(&str, &'static str)> {
+  let kept = [
+    (SNAPSHOT_EXTENSION, "snapshot"),
+    (SCHEMA_EXTENSION, "schema"),
+    (INDEX_EXTENSION, "index file"),
+  ];
+  for (extension, kept) in kept {
+    if let Some(base) = name.strip_suffix(&format!(".{}", extension)) {
+      return Some((base, kept));
+    }
+  }
+  None
+}
+
+/// Files in a database directory that belong to no topic, with the reason
+/// each is out of place. Topic files and directories are left to the topic
+/// and directory they belong to.
+pub fn stray_files(directory: &Path) -> io::Result<Vec<(PathBuf, String)>> {
+  let mut strays: Vec<(PathBuf, String)> = Vec::new();
+  for entry in fs::read_dir(directory)? {
+    let entry = entry?;
+    let path = entry.path();
+    let name = entry.file_name().to_string_lossy().to_string();
+    let file_type = entry.file_type()?;
+    let reason = if file_type.is_dir() {
+      continue;
+    } else if !file_type.is_file() {
+      "not a regular file".to_string()
+    } else if path
+      .extension()
+      .is_some_and(|extension| extension == TOPIC_EXTENSION)
+    {
+      continue;
+    } else if let Some((base, kept)) = kept_with_topic(&name) {
+      if directory
+        .join(format!("{}.{}", base, TOPIC_EXTENSION))
+        .is_file()
+      {
+        continue;
+      }
+      format!("{} of a topic that does not exist", kept)
+    } else if let Some(source) = backup_source(&path) {
+      let is_topic = source
+        .extension()
+        .is_some_and(|extension| extension == TOPIC_EXTENSION);
+      let stamp = name.rsplit_once(".bkp_").map(|(_, stamp)| stamp);
+      let valid_stamp =
+        stamp.is_some_and(|stamp| NaiveDateTime::parse_from_str(stamp, BACKUP_STAMP).is_ok());
+      if !is_topic || !valid_stamp {
+        "unrecognised backup".to_string()
+      } else if source.is_file() {
+        continue;
+      } else {
+        "backup of a topic that does not exist".to_string()
+      }
+    } else if is_temporary(&name) {
+      "left over from an interrupted write".to_string()
+    } else {
+      "unknown file type".to_string()
+    };
+    strays.push((path, reason));
+  }
+  Ok(strays)
+}
+
+/// True for the names of files written and then renamed into place, which
+/// are only left behind when a write was interrupted.
+fn is_temporary(name: &str) -> bool {
+  [
+    WRITE_TEMPORARY,
+    REPAIR_TEMPORARY,
+    MIGRATE_TEMPORARY,
+    RESTORE_TEMPORARY,
+  ]
+  .iter()
+  .any(|extension| name.ends_with(&format!(".{}", extension)))
+}
+
+/// Path of the file a backup was taken from.
+pub fn backup_source(backup: &Path) -> Option<PathBuf> {
+  let name = backup.file_name()?.to_str()?;
+  let (source, _) = name.rsplit_once(".bkp_")?;
+  Some(backup.with_file_name(source))
+}
+
+/// Time stamp of a backup file name. For a single file topic the name has
+/// already had the topic's file name and dot removed; a segment backup still
+/// starts with the segment's name, and may be the marker of a missing
+/// segment.
+fn backup_stamp(name: &str) -> Option<&str> {
+  if let Some(stamp) = name.strip_prefix("bkp_") {
+    return Some(stamp);
+  }
+  let (segment, stamp) = name.split_once(".bkp_")?;
+  let stamp = stamp
+    .strip_suffix(&format!(".{}", MISSING_MARKER))
+    .unwrap_or(stamp);
+  segment_number(Path::new(segment)).map(|_| stamp)
+}
+
+fn segment_name(number: u64) -> String {
+  format!("{:08}.{}", number, SEGMENT_EXTENSION)
+}
+
+fn segment_number(path: &Path) -> Option<u64> {
+  if path.extension()? != SEGMENT_EXTENSION {
+    return None;
+  }
+  path.file_stem()?.to_str()?.parse::<u64>().ok()
+}
@@ -1,184 +1,1751 @@
+use crate::check::{DatabaseReport, Problem, StrayFile, TopicReport};
+use crate::config::{Durability, EngineConfig};
 use crate::dbprocess::ContextController;
 use crate::dbprocess::ContextProcess;
 use crate::dbprocess::DBResponse;
+use crate::documents::{self, Condition, Operator};
+use crate::indexes::{Index, Indexes};
+use crate::migrate::{self, Migration, MigrationReport};
+use crate::positions::{self, Position};
+use crate::queries::{ListQuery, SortKey};
+use crate::records::{check_key, Record};
+use crate::records::{Content, Encoding, Format, FormatVersion, LogReader, CURRENT_VERSION};
+use crate::records::{ACTION_ADD, ACTION_DELETE, ACTION_INSERT, ACTION_MOVE, ACTION_UPDATE};
+use crate::schemas::{Field, Schema};
+use crate::search::{self, SearchIndex, SearchResults};
+use crate::snapshots::Snapshot;
+use crate::storage::{self, backup_path, backup_source, temporary_path, Storage};
+use crate::storage::{BACKUP_STAMP, RESTORE_TEMPORARY, SEGMENTED_EXTENSION, TOPIC_EXTENSION};
+use crate::verify;
 use chrono::prelude::*;
-use log::debug;
-use std::collections::HashMap;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::prelude::*;
-use std::io::Write;
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use uuid::Uuid;
 
-const ACTION_ADD: &str = "A";
-const ACTION_DELETE: &str = "D";
-const ACTION_UPDATE: &str = "U";
+/// Most ids listed when an id prefix matches more than one record.
+const MAX_CANDIDATES: usize = 10;
+/// Fewest characters an id prefix must have to stand for a record.
+const MIN_PREFIX_LENGTH: usize = 4;
 
+/// Point in a topic's log given by `OPEN TOPIC <id> AS OF <point>`.
+#[derive(Clone, Copy)]
+enum AsOf {
+  Sequence(u64),
+  Time(DateTime<Utc>),
+}
+
+impl AsOf {
+  /// Parses a sequence number or a local date and time. Accepts RFC 3339,
+  /// `YYYY-MM-DD HH:MM:SS` and `YYYY-MM-DD` (the start of that day).
+  fn parse(point: &str) -> Result<AsOf, String> {
+    let point = point.trim();
+    if let Ok(seq) = point.parse::<u64>() {
+      return Ok(AsOf::Sequence(seq));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(point) {
+      return Ok(AsOf::Time(time.with_timezone(&Utc)));
+    }
+    let naive = NaiveDateTime::parse_from_str(point, "%Y-%m-%d %H:%M:%S")
+      .or_else(|_| NaiveDateTime::parse_from_str(point, "%Y-%m-%dT%H:%M:%S"))
+      .ok()
+      .or_else(|| {
+        NaiveDate::parse_from_str(point, "%Y-%m-%d")
+          .ok()
+          .and_then(|date| date.and_hms_opt(0, 0, 0))
+      });
+    match naive.and_then(|naive| Local.from_local_datetime(&naive).earliest()) {
+      Some(time) => Ok(AsOf::Time(time.with_timezone(&Utc))),
+      None => Err(format!(
+        "Invalid AS OF \"{}\". (expected a sequence number or a date and time)",
+        point
+      )),
+    }
+  }
+
+  /// Records without a time were migrated from a format that had none, so
+  /// were written before any record that has one; they are always included.
+  fn includes(&self, record: &Record) -> bool {
+    match self {
+      AsOf::Sequence(seq) => record.seq <= *seq,
+      AsOf::Time(time) => record.timestamp.is_none_or(|stamp| stamp <= *time),
+    }
+  }
+
+  fn describe(&self) -> String {
+    match self {
+      AsOf::Sequence(seq) => format!("AS OF #{}", seq),
+      AsOf::Time(time) => format!("AS OF {}", display_time(&Some(*time))),
+    }
+  }
+}
+
+/// Current state of a record: the record that added it and the most recent
+/// record written for its id.
 #[derive(Clone)]
-struct Record {
-  id: String,
-  action: String,
-  content: String,
+struct Entry {
+  added: Record,
+  latest: Record,
+  /// Where the record is in the list.
+  position: Position,
+  /// The most recent `MOVE` of the record.
+  moved: Option<Record>,
+}
+
+impl Entry {
+  /// The records that rebuild the entry when replayed, in log order: the
+  /// records compaction and snapshots keep.
+  fn records(&self) -> Vec<&Record> {
+    let mut records: Vec<&Record> = vec![&self.added];
+    if self.latest.seq != self.added.seq {
+      records.push(&self.latest);
+    }
+    records.extend(self.moved.as_ref());
+    records.sort_by_key(|record| record.seq);
+    records
+  }
+
+  /// Current content of the record. An `INSERT` record's content starts
+  /// with the position it was inserted at, which is not part of it.
+  fn content(&self) -> &str {
+    match positions::split_insert(&self.latest.content) {
+      Ok((_, content)) if self.latest.action == ACTION_INSERT => content,
+      _ => &self.latest.content,
+    }
+  }
 }
 
 struct Topic {
-  path: String,
+  storage: Storage,
+  /// File new records are appended to: the topic file or its last segment.
+  active: PathBuf,
   id: String,
-  record_map: HashMap<String, Record>,
+  record_map: HashMap<String, Entry>,
+  /// Sequence number of the last record in the topic.
+  last_seq: u64,
+  /// Number of records in the log, live or dead.
+  total_records: usize,
+  format: Format,
+  /// Fields the content of new records is checked against.
+  schema: Schema,
+  /// Indexes on fields of the records, built as the topic is loaded.
+  indexes: Indexes,
+  /// Terms of the live records, built the first time the topic is searched.
+  search_index: Option<SearchIndex>,
+  config: EngineConfig,
+  /// Offset and length of a damaged tail that was left in place.
+  damaged_tail: Option<(usize, usize)>,
+  /// Point in the log the topic was opened at. Such a topic is read-only.
+  as_of: Option<AsOf>,
+  /// True when the topic was opened to be read without changing its files:
+  /// a damaged tail is left in place and no snapshot is written.
+  read_only: bool,
+  /// Length of the active file.
+  active_len: u64,
+  /// Byte offset of the last record in the active file.
+  last_offset: Option<usize>,
+  /// Sequence number of the record at `last_offset`. Compaction can carry
+  /// a higher `last_seq` over in the header, so the two may differ.
+  last_offset_seq: u64,
+  /// Records written or replayed since the last snapshot.
+  unsnapshotted: usize,
+  /// True when writes have been made since the active file was last synced.
+  unsynced: bool,
+  last_sync: Instant,
+  /// Writer for the active file, kept open between writes.
+  writer: Option<BufWriter<File>>,
 }
 
 impl Topic {
-  pub fn new(topic_id: &str, topic_path: &str) -> Topic {
+  pub fn new(
+    topic_id: &str,
+    storage: Storage,
+    config: &EngineConfig,
+    as_of: Option<AsOf>,
+  ) -> Result<Topic, String> {
+    Topic::open(topic_id, storage, config, as_of, false)
+  }
+
+  /// Opens the topic to be read by a command that must not change its
+  /// files, such as a search of a whole directory.
+  pub fn read_only(
+    topic_id: &str,
+    storage: Storage,
+    config: &EngineConfig,
+  ) -> Result<Topic, String> {
+    Topic::open(topic_id, storage, config, None, true)
+  }
+
+  fn open(
+    topic_id: &str,
+    storage: Storage,
+    config: &EngineConfig,
+    as_of: Option<AsOf>,
+    read_only: bool,
+  ) -> Result<Topic, String> {
     let mut topic = Topic {
-      path: topic_path.to_string(),
+      active: storage.path().to_path_buf(),
+      storage,
       id: topic_id.to_string(),
       record_map: HashMap::new(),
+      last_seq: 0,
+      total_records: 0,
+      format: Format::current(Encoding::Line),
+      schema: Schema::default(),
+      indexes: Indexes::default(),
+      search_index: None,
+      config: config.clone(),
+      damaged_tail: None,
+      as_of,
+      read_only,
+      active_len: 0,
+      last_offset: None,
+      last_offset_seq: 0,
+      unsnapshotted: 0,
+      unsynced: false,
+      last_sync: Instant::now(),
+      writer: None,
+    };
+    topic.load()?;
+    Ok(topic)
+  }
+
+  /// The topic's files, oldest first.
+  fn log_files(&self) -> Result<Vec<PathBuf>, String> {
+    let files = match self.storage.files() {
+      Ok(files) => files,
+      Err(error) => return Err(format!("Unable to read topic {}: {}", self.id, error)),
     };
-    let records = Topic::get_records(topic_path);
-    for record in records {
-      if record.action == ACTION_DELETE {
-        if topic.record_map.contains_key(&record.id) {
-          topic.record_map.remove(&record.id);
+    if files.is_empty() {
+      return Err(format!("Topic {} has no segments.", self.id));
+    }
+    Ok(files)
+  }
+
+  /// Opens one of the topic's files for reading from byte `start`.
+  fn open_file(&self, file: &Path, start: usize) -> Result<LogReader<BufReader<File>>, String> {
+    LogReader::open(file, start)
+      .map_err(|message| format!("Unable to read topic {}: {}", self.id, message))
+  }
+
+  /// Reads the next record of one of the topic's files. Records of files
+  /// without sequence numbers are numbered on from `numbered`, the number
+  /// of records in the files before it.
+  fn read_record(
+    &self,
+    reader: &mut LogReader<BufReader<File>>,
+    numbered: u64,
+  ) -> Result<Option<Record>, String> {
+    match reader.next_record() {
+      Ok(Some(mut record)) => {
+        if !reader.format.has_stamps() {
+          record.seq += numbered;
+        }
+        Ok(Some(record))
+      }
+      Ok(None) => Ok(None),
+      Err(message) => Err(format!("Unable to read topic {}: {}", self.id, message)),
+    }
+  }
+
+  /// Checks that the log still holds the last record covered by a snapshot
+  /// where the snapshot says it is. Returns the index of the file to resume
+  /// from and a reader positioned just after that record.
+  fn check_snapshot(
+    &self,
+    files: &[PathBuf],
+    snapshot: &Snapshot,
+  ) -> Result<(usize, LogReader<BufReader<File>>), String> {
+    let index = files
+      .iter()
+      .position(|file| file.file_name().is_some_and(|name| *name == *snapshot.file))
+      .ok_or_else(|| format!("{} no longer exists", snapshot.file))?;
+    let mut reader = LogReader::open(&files[index], snapshot.offset)?;
+    if let Some(check_seq) = snapshot.check_seq {
+      let record = reader.next_record()?;
+      if reader.last_offset != Some(snapshot.offset)
+        || record.map(|record| record.seq) != Some(check_seq)
+      {
+        return Err(format!(
+          "record #{} is not at byte {}",
+          check_seq, snapshot.offset
+        ));
+      }
+    }
+    Ok((index, reader))
+  }
+
+  /// Replays the topic's files into `record_map`, stopping at `as_of` when
+  /// the topic was opened at an earlier point. Starts from the topic's
+  /// snapshot when there is a usable one. Records are read one at a time,
+  /// so memory use depends on the number of live records rather than the
+  /// size of the log. Corrupt records are skipped and reported with their
+  /// byte offset. A damaged tail on the file being appended to is either
+  /// truncated or, depending on the configuration, reported and left in
+  /// place.
+  fn load(&mut self) -> Result<(), String> {
+    self.release_writer()?;
+    self.schema = Schema::read(&self.storage.schema_path())
+      .map_err(|error| format!("Unable to read the schema of topic {}: {}", self.id, error))?;
+    self.indexes = Indexes::read(&self.storage.index_path())
+      .map_err(|error| format!("Unable to read the indexes of topic {}: {}", self.id, error))?;
+    let files = self.log_files()?;
+    let snapshot = match self.read_snapshot() {
+      Some(snapshot) => match self.check_snapshot(&files, &snapshot) {
+        Ok((index, reader)) => Some((snapshot, index, reader)),
+        Err(message) => {
+          warn!("topic {}: snapshot not used, {}", self.id, message);
+          None
+        }
+      },
+      None => None,
+    };
+    self.damaged_tail = None;
+    self.search_index = None;
+    self.record_map.clear();
+    self.last_seq = 0;
+    self.total_records = 0;
+    let (first, mut resumed) = match snapshot {
+      Some((snapshot, index, reader)) => {
+        debug!(
+          "topic {}: loaded snapshot of {} records",
+          self.id, snapshot.total_records
+        );
+        for record in snapshot.records {
+          self.replay(record);
+        }
+        self.last_seq = snapshot.last_seq;
+        self.total_records = snapshot.total_records;
+        (index, Some(reader))
+      }
+      None => (0, None),
+    };
+    let from_snapshot = self.total_records;
+    let last = files.len() - 1;
+    let mut numbered: u64 = 0;
+    for (index, file) in files.into_iter().enumerate().skip(first) {
+      let mut reader = match resumed.take() {
+        Some(reader) => reader,
+        None => self.open_file(&file, 0)?,
+      };
+      if let Some(AsOf::Time(_)) = self.as_of {
+        if !reader.format.has_stamps() {
+          return Err(format!(
+            "Topic {} does not record timestamps. Use a sequence number with AS OF.",
+            self.id
+          ));
+        }
+      }
+      self.last_seq = self.last_seq.max(reader.format.prior_seq);
+      while let Some(record) = self.read_record(&mut reader, numbered)? {
+        self.replay(record);
+      }
+      numbered += reader.count;
+      for (offset, _, reason) in &reader.corrupt {
+        if *offset < reader.valid_len {
+          warn!(
+            "topic {}: skipped corrupt record at byte {} of {} ({})",
+            self.id,
+            offset,
+            file.display(),
+            reason
+          );
+        }
+      }
+      let valid_len = reader.valid_len;
+      let damaged_len = reader.file_len() - valid_len;
+      if index == last {
+        self.format = reader.format;
+        self.active_len = valid_len as u64;
+        self.last_offset = reader.last_offset;
+        self.last_offset_seq = reader.last_seq.unwrap_or_default();
+      }
+      drop(reader);
+      if damaged_len > 0 {
+        if index != last {
+          warn!(
+            "topic {}: {} damaged bytes at byte {} of {}",
+            self.id,
+            damaged_len,
+            valid_len,
+            file.display()
+          );
+        } else if self.config.truncate_damaged_tail && self.as_of.is_none() && !self.read_only {
+          warn!(
+            "topic {}: truncating {} damaged bytes at byte {}",
+            self.id, damaged_len, valid_len
+          );
+          let truncated = OpenOptions::new()
+            .write(true)
+            .open(&file)
+            .and_then(|file| file.set_len(valid_len as u64));
+          if let Err(error) = truncated {
+            return Err(format!("Unable to truncate topic {}: {}", self.id, error));
+          }
+        } else {
+          warn!(
+            "topic {}: {} damaged bytes at byte {}",
+            self.id, damaged_len, valid_len
+          );
+          self.damaged_tail = Some((valid_len, damaged_len));
         }
-      } else {
-        topic.record_map.insert(record.id.clone(), record.clone());
+      }
+      if index == last {
+        self.active = file;
       }
     }
+    self.rebuild_indexes();
+    self.unsnapshotted = self.total_records - from_snapshot;
+    self.snapshot_if_due();
+    Ok(())
+  }
 
-    return topic;
+  /// The topic's snapshot, if snapshots are enabled and one can be read.
+  /// Views at an earlier point always replay the whole log.
+  fn read_snapshot(&self) -> Option<Snapshot> {
+    if self.as_of.is_some() || self.config.snapshot_interval.is_none() {
+      return None;
+    }
+    let path = self.storage.snapshot_path();
+    if !path.exists() {
+      return None;
+    }
+    match Snapshot::read(&path) {
+      Ok(snapshot) => Some(snapshot),
+      Err(message) => {
+        warn!("topic {}: snapshot not used, {}", self.id, message);
+        None
+      }
+    }
   }
 
-  fn get_records(path: &str) -> Vec<Record> {
-    let mut file = OpenOptions::new().read(true).open(path).unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let mut lines: Vec<&str> = contents.split('\n').collect();
-    lines.pop();
+  /// Writes a snapshot once the configured number of records have been
+  /// written or replayed since the last one. Snapshots are only taken of
+  /// topics whose records carry sequence numbers, as those are used to
+  /// check the snapshot against the log.
+  fn snapshot_if_due(&mut self) {
+    let due = self
+      .config
+      .snapshot_interval
+      .is_some_and(|interval| self.unsnapshotted >= interval);
+    if !due || self.write_blocked().is_some() || !self.format.has_stamps() {
+      return;
+    }
+    if let Err(message) = self.flush_pending() {
+      warn!("{}", message);
+      return;
+    }
+    let file = match self.active.file_name() {
+      Some(name) => name.to_string_lossy().to_string(),
+      None => return,
+    };
     let mut records: Vec<Record> = Vec::new();
-    for line in lines {
-      if line.len() > 37 {
-        let record = Record {
-          id: line[0..36].to_string(),
-          action: line[36..37].to_string(),
-          content: line[37..].to_string(),
+    for entry in self.ordered_entries() {
+      records.extend(entry.records().into_iter().cloned());
+    }
+    let snapshot = Snapshot {
+      file,
+      offset: self
+        .last_offset
+        .unwrap_or_else(|| self.format.header().len()),
+      check_seq: self.last_offset.map(|_| self.last_offset_seq),
+      last_seq: self.last_seq,
+      total_records: self.total_records,
+      records,
+    };
+    match snapshot.write(&self.storage.snapshot_path()) {
+      Ok(_) => {
+        debug!(
+          "topic {}: wrote snapshot at record #{}",
+          self.id, self.last_seq
+        );
+        self.unsnapshotted = 0;
+      }
+      Err(error) => warn!("topic {}: unable to write snapshot: {}", self.id, error),
+    }
+  }
+
+  fn replay(&mut self, record: Record) {
+    if let Some(as_of) = self.as_of {
+      if !as_of.includes(&record) {
+        return;
+      }
+    }
+    self.last_seq = self.last_seq.max(record.seq);
+    self.total_records += 1;
+    self.apply(record);
+  }
+
+  /// Applies a record just written to the log to the live entries and
+  /// keeps the indexes and search index up to date with the record's new
+  /// content.
+  fn apply_written(&mut self, record: Record) {
+    let indexed = !self.indexes.is_empty() || self.search_index.is_some();
+    if !indexed || record.action == ACTION_MOVE {
+      self.apply(record);
+      return;
+    }
+    let id = record.id.clone();
+    if let Some(entry) = self.record_map.get(&id) {
+      self.indexes.remove(&id, entry.content());
+      if let Some(search_index) = self.search_index.as_mut() {
+        search_index.remove(&id, entry.content());
+      }
+    }
+    self.apply(record);
+    if let Some(entry) = self.record_map.get(&id) {
+      self.indexes.insert(&id, entry.content());
+      if let Some(search_index) = self.search_index.as_mut() {
+        search_index.insert(&id, entry.content());
+      }
+    }
+  }
+
+  /// Builds the indexes from the live entries.
+  fn rebuild_indexes(&mut self) {
+    if self.indexes.is_empty() {
+      return;
+    }
+    let records = self
+      .record_map
+      .iter()
+      .map(|(id, entry)| (id.as_str(), entry.content()));
+    self.indexes.rebuild(records);
+  }
+
+  /// Applies a record to the live entries.
+  fn apply(&mut self, record: Record) {
+    match record.action.as_str() {
+      ACTION_DELETE => {
+        self.record_map.remove(&record.id);
+      }
+      ACTION_MOVE => match Position::parse(&record.content) {
+        Ok(position) => {
+          if let Some(entry) = self.record_map.get_mut(&record.id) {
+            entry.position = position;
+            entry.moved = Some(record);
+          }
+        }
+        Err(problem) => warn!(
+          "topic {}: record #{} has an {}",
+          self.id, record.seq, problem
+        ),
+      },
+      _ if self.record_map.contains_key(&record.id) => {
+        if let Some(entry) = self.record_map.get_mut(&record.id) {
+          entry.latest = record;
+        }
+      }
+      ACTION_INSERT => match positions::split_insert(&record.content) {
+        Ok((position, _)) => {
+          let entry = Entry {
+            added: record.clone(),
+            latest: record,
+            position,
+            moved: None,
+          };
+          self.record_map.insert(entry.added.id.clone(), entry);
+        }
+        Err(problem) => warn!(
+          "topic {}: record #{} has an {}",
+          self.id, record.seq, problem
+        ),
+      },
+      _ => {
+        let entry = Entry {
+          added: record.clone(),
+          latest: record.clone(),
+          position: Position::added(record.seq),
+          moved: None,
         };
-        records.push(record);
+        self.record_map.insert(entry.added.id.clone(), entry);
       }
     }
-    return records;
   }
 
-  fn append_data(&self, record: &Record) {
-    let output = format!("{}{}{}\n", record.id, record.action, record.content);
-    let mut file = OpenOptions::new().append(true).open(&self.path).unwrap();
-    file.write_all(output.as_bytes()).expect("Add failed");
+  /// The live entries in list order: the order they were added in, unless
+  /// they were inserted or moved elsewhere. An update keeps the entry where
+  /// it was.
+  fn ordered_entries(&self) -> Vec<&Entry> {
+    let mut entries: Vec<&Entry> = self.record_map.values().collect();
+    entries.sort_by(|a, b| a.position.cmp(&b.position));
+    entries
+  }
+
+  /// Builds the next record to append to the topic. The topic's counts
+  /// only move on once `append_data` has written it.
+  fn next_record(&self, id: &str, action: &str, content: &str) -> Record {
+    let timestamp = if self.format.has_stamps() {
+      Some(Utc::now())
+    } else {
+      None
+    };
+    Record {
+      id: id.to_string(),
+      action: action.to_string(),
+      content: content.to_string(),
+      seq: self.last_seq + 1,
+      timestamp,
+    }
+  }
+
+  /// Number of records compaction would keep: the add of each live entry,
+  /// its latest update and its latest move.
+  fn live_records(&self) -> usize {
+    self
+      .record_map
+      .values()
+      .map(|entry| entry.records().len())
+      .sum()
+  }
+
+  /// Compacts the topic if its dead records cross one of the configured
+  /// thresholds. Called when the topic is closed.
+  fn auto_compact(&mut self) {
+    if self.write_blocked().is_some() {
+      return;
+    }
+    let dead_records = self.total_records.saturating_sub(self.live_records());
+    if dead_records == 0 {
+      return;
+    }
+    let dead_ratio = dead_records as f64 / self.total_records as f64;
+    let file_size = self.storage.size();
+    let over_ratio = self
+      .config
+      .auto_compact_dead_ratio
+      .is_some_and(|threshold| dead_ratio >= threshold);
+    let over_size = self
+      .config
+      .auto_compact_file_size
+      .is_some_and(|threshold| file_size >= threshold);
+    if !over_ratio && !over_size {
+      return;
+    }
+    info!(
+      "topic {}: compacting, {} of {} records dead, {} bytes",
+      self.id, dead_records, self.total_records, file_size
+    );
+    match self.compact() {
+      DBResponse::ROk(_) => {
+        remove_expired_backups(&self.storage, &self.config);
+      }
+      DBResponse::Error(message) => warn!("topic {}: {}", self.id, message),
+      _ => (),
+    }
+  }
+
+  /// Reason the topic cannot be written to, if any.
+  fn write_blocked(&self) -> Option<String> {
+    if let Some(as_of) = self.as_of {
+      return Some(format!(
+        "Topic {} is open {} and is read-only.",
+        self.id,
+        as_of.describe()
+      ));
+    }
+    if self.read_only {
+      return Some(format!("Topic {} is open read-only.", self.id));
+    }
+    self.damaged_tail.map(|(offset, _)| {
+      format!(
+        "Topic {} has a damaged tail at byte {}. Compact the topic or reopen it with truncation enabled.",
+        self.id, offset
+      )
+    })
+  }
+
+  /// Appends a record to the topic, starting a new segment first if the
+  /// last one has reached the configured size. The record is written
+  /// through the topic's buffered writer and written out and synced as the
+  /// configuration requires.
+  fn append_data(&mut self, record: &Record) -> Result<(), String> {
+    let topic_id = self.id.clone();
+    let failed =
+      |error: std::io::Error| format!("Unable to write to topic {}: {}", topic_id, error);
+    if self.storage.is_segmented() && self.active_len >= self.config.segment_size {
+      self.release_writer()?;
+      self.format.prior_seq = 0;
+      let header = self.format.header();
+      self.active = self.storage.roll(&self.active, &header).map_err(failed)?;
+      self.active_len = header.len() as u64;
+      self.last_offset = None;
+      debug!(
+        "topic {}: started segment {}",
+        self.id,
+        self.active.display()
+      );
+    }
+    let output = self.format.encode(record);
+    let sync_due = match self.config.durability {
+      Durability::None => false,
+      Durability::EveryWrite => true,
+      Durability::Interval(interval) => self.last_sync.elapsed() >= interval,
+    };
+    let write_through = sync_due || self.config.write_buffer == 0;
+    let writer = self.writer().map_err(failed)?;
+    writer.write_all(&output).map_err(failed)?;
+    if write_through {
+      writer.flush().map_err(failed)?;
+    }
+    if sync_due {
+      writer.get_ref().sync_data().map_err(failed)?;
+    }
+    self.last_seq = record.seq;
+    self.total_records += 1;
+    self.last_offset = Some(self.active_len as usize);
+    self.last_offset_seq = record.seq;
+    self.active_len += output.len() as u64;
+    self.unsnapshotted += 1;
+    self.unsynced = !sync_due;
+    if sync_due {
+      self.last_sync = Instant::now();
+    }
+    Ok(())
+  }
+
+  /// Writer for the active file, opened on first use.
+  fn writer(&mut self) -> std::io::Result<&mut BufWriter<File>> {
+    if self.writer.is_none() {
+      let file = OpenOptions::new().append(true).open(&self.active)?;
+      self.writer = Some(BufWriter::with_capacity(self.config.write_buffer, file));
+    }
+    Ok(self.writer.as_mut().expect("writer was just opened"))
+  }
+
+  /// Writes out buffered records and syncs writes still waiting on the
+  /// durability interval.
+  fn flush_pending(&mut self) -> Result<(), String> {
+    let topic_id = &self.id;
+    let failed =
+      |error: std::io::Error| format!("Unable to write to topic {}: {}", topic_id, error);
+    let writer = match self.writer.as_mut() {
+      Some(writer) => writer,
+      None => return Ok(()),
+    };
+    writer.flush().map_err(failed)?;
+    if self.unsynced && self.config.durability != Durability::None {
+      writer.get_ref().sync_data().map_err(failed)?;
+      self.unsynced = false;
+      self.last_sync = Instant::now();
+    }
+    Ok(())
+  }
+
+  /// Writes out pending records and closes the writer, before the active
+  /// file is replaced or its files are read or rewritten.
+  fn release_writer(&mut self) -> Result<(), String> {
+    self.flush_pending()?;
+    self.writer = None;
+    Ok(())
   }
 
   fn add(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    if args.len() == 0 {
+    if args.is_empty() {
       return DBResponse::Invalid("Content for ADD cannot be empty.".to_string());
     }
+    if let Some(message) = self.write_blocked() {
+      return DBResponse::Invalid(message);
+    }
     let output = args.join(" ");
+    if let Some(message) = self.reject_content(&output) {
+      return DBResponse::Invalid(message);
+    }
     let id = Uuid::new_v4();
-    let record = Record {
-      id: id.to_string(),
-      action: ACTION_ADD.to_string(),
-      content: output,
+    let record = self.next_record(&id.to_string(), ACTION_ADD, &output);
+    if let Err(message) = self.append_data(&record) {
+      return DBResponse::Error(message);
+    }
+    self.apply_written(record);
+    self.snapshot_if_due();
+    DBResponse::Created(id.to_string())
+  }
+
+  /// Adds a record at a position in the list, counted from 1. A position
+  /// one past the end adds it at the end.
+  fn insert(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let args = match args.first() {
+      Some(keyword) if keyword.eq_ignore_ascii_case("AT") => &args[1..],
+      _ => args,
+    };
+    if args.len() < 2 {
+      return DBResponse::Invalid("INSERT requires a position and content".to_string());
+    }
+    let blocked = self.write_blocked();
+    if let Some(message) = blocked.or_else(|| self.format_blocked("insert or move records")) {
+      return DBResponse::Invalid(message);
+    }
+    let entries = self.ordered_entries();
+    let index = match args[0].parse::<usize>() {
+      Ok(number) if number >= 1 && number <= entries.len() + 1 => number - 1,
+      _ => {
+        let message = format!(
+          "Invalid position {}. (expected 1 to {})",
+          args[0],
+          entries.len() + 1
+        );
+        return DBResponse::Invalid(message);
+      }
     };
-    self.append_data(&record);
-    self.record_map.insert(record.id.clone(), record.clone());
+    let position = position_at(&entries, index);
+    let content = args[1..].join(" ");
+    if let Some(message) = self.reject_content(&content) {
+      return DBResponse::Invalid(message);
+    }
+    let id = Uuid::new_v4();
+    let output = format!("{} {}", position, content);
+    let record = self.next_record(&id.to_string(), ACTION_INSERT, &output);
+    if let Err(message) = self.append_data(&record) {
+      return DBResponse::Error(message);
+    }
+    self.apply_written(record);
+    self.snapshot_if_due();
     DBResponse::Created(id.to_string())
   }
 
+  /// Moves a record `BEFORE` or `AFTER` another, or `TO` a position in the
+  /// list counted from 1. Nothing is written if the record is already
+  /// there.
+  fn move_record(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if args.len() != 3 {
+      return DBResponse::Invalid(
+        "MOVE requires a key, BEFORE, AFTER or TO, and a key or position".to_string(),
+      );
+    }
+    let blocked = self.write_blocked();
+    if let Some(message) = blocked.or_else(|| self.format_blocked("insert or move records")) {
+      return DBResponse::Invalid(message);
+    }
+    let selected_record = match self.resolve_id(args[0]) {
+      Ok(id) => id,
+      Err(message) => return DBResponse::Invalid(message),
+    };
+    let ordered = self.ordered_entries();
+    let current = ordered
+      .iter()
+      .position(|entry| entry.added.id == selected_record);
+    let entries: Vec<&Entry> = ordered
+      .into_iter()
+      .filter(|entry| entry.added.id != selected_record)
+      .collect();
+    let target = args[2];
+    let index = match args[1].to_uppercase().as_str() {
+      "TO" => match target.parse::<usize>() {
+        Ok(number) if number >= 1 && number <= entries.len() + 1 => number - 1,
+        _ => {
+          let message = format!(
+            "Invalid position {}. (expected 1 to {})",
+            target,
+            entries.len() + 1
+          );
+          return DBResponse::Invalid(message);
+        }
+      },
+      direction @ ("BEFORE" | "AFTER") => {
+        let target = match self.resolve_id(target) {
+          Ok(id) => id,
+          Err(message) => return DBResponse::Invalid(message),
+        };
+        if target == selected_record {
+          return DBResponse::Invalid("A record cannot be moved relative to itself.".to_string());
+        }
+        match entries.iter().position(|entry| entry.added.id == target) {
+          Some(index) if direction == "BEFORE" => index,
+          Some(index) => index + 1,
+          None => return DBResponse::Invalid(format!("Record {} does not exist.", target)),
+        }
+      }
+      other => {
+        let message = format!(
+          "Unknown MOVE option {}. (expected BEFORE, AFTER or TO)",
+          other
+        );
+        return DBResponse::Invalid(message);
+      }
+    };
+    if current == Some(index) {
+      return DBResponse::ROk(format!(
+        "Record {} is already at position {}.",
+        selected_record,
+        index + 1
+      ));
+    }
+    let position = position_at(&entries, index);
+    let record = self.next_record(&selected_record, ACTION_MOVE, &position.to_string());
+    if let Err(message) = self.append_data(&record) {
+      return DBResponse::Error(message);
+    }
+    self.apply_written(record);
+    self.snapshot_if_due();
+    DBResponse::ROk(format!(
+      "Record {} moved to position {}.",
+      selected_record,
+      index + 1
+    ))
+  }
+
+  /// Reason the topic's format does not allow `action`, if it does not.
+  /// List positions and keys are only stored by formats with sequence
+  /// numbers.
+  fn format_blocked(&self, action: &str) -> Option<String> {
+    if self.format.has_stamps() {
+      return None;
+    }
+    Some(format!(
+      "Topic {} is in format {}. Migrate it to {}.",
+      self.id, self.format.version, action
+    ))
+  }
+
+  /// Reason a record's content cannot be written to the topic, if it
+  /// cannot: the format cannot hold it or the schema does not accept it.
+  fn reject_content(&self, content: &str) -> Option<String> {
+    self
+      .format
+      .reject_content(content)
+      .or_else(|| self.schema.reject(content))
+  }
+
+  /// Adds a record under a key chosen by the caller, or updates the record
+  /// if the key is already in use.
+  fn put(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if args.len() < 2 {
+      return DBResponse::Invalid("PUT requires a key and content".to_string());
+    }
+    let blocked = self.write_blocked();
+    if let Some(message) = blocked.or_else(|| self.format_blocked("use record keys")) {
+      return DBResponse::Invalid(message);
+    }
+    let key = args[0];
+    if let Err(message) = check_key(key) {
+      return DBResponse::Invalid(message);
+    }
+    if self.record_map.contains_key(key) {
+      return self.update(args);
+    }
+    let content = args[1..].join(" ");
+    if let Some(message) = self.reject_content(&content) {
+      return DBResponse::Invalid(message);
+    }
+    let record = self.next_record(key, ACTION_ADD, &content);
+    if let Err(message) = self.append_data(&record) {
+      return DBResponse::Error(message);
+    }
+    self.apply_written(record);
+    self.snapshot_if_due();
+    DBResponse::Created(key.to_string())
+  }
+
+  /// Returns the content of a single record.
+  fn get(&self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if args.len() != 1 {
+      return DBResponse::Invalid("GET requires a key".to_string());
+    }
+    let selected_record = match self.resolve_id(args[0]) {
+      Ok(id) => id,
+      Err(message) => return DBResponse::Invalid(message),
+    };
+    let entry = &self.record_map[&selected_record];
+    let item = (entry.latest.id.to_string(), entry.content().to_string());
+    DBResponse::Data(vec![item])
+  }
+
+  /// Resolves an id given to a command to the id of a live record. Like a
+  /// short git hash, any prefix of at least `MIN_PREFIX_LENGTH` characters
+  /// that matches only one id will do.
+  fn resolve_id(&self, id: &str) -> Result<String, String> {
+    if id.is_empty() {
+      return Err("A record id is required.".to_string());
+    }
+    if self.record_map.contains_key(id) {
+      return Ok(id.to_string());
+    }
+    let candidates = self.prefix_candidates(id);
+    match candidates.as_slice() {
+      [] => Err(format!("Record {} does not exist.", id)),
+      [candidate] => Ok(candidate.to_string()),
+      _ => {
+        let shown: Vec<&str> = candidates
+          .iter()
+          .take(MAX_CANDIDATES)
+          .map(|candidate| candidate.as_str())
+          .collect();
+        let more = if candidates.len() > MAX_CANDIDATES {
+          format!(" and {} more", candidates.len() - MAX_CANDIDATES)
+        } else {
+          "".to_string()
+        };
+        Err(format!(
+          "Record {} is ambiguous. (matches {}{})",
+          id,
+          shown.join(", "),
+          more
+        ))
+      }
+    }
+  }
+
+  /// Ids of the live records starting with `id`, in order, or none if `id`
+  /// is too short to be a prefix.
+  fn prefix_candidates(&self, id: &str) -> Vec<&String> {
+    if id.chars().count() < MIN_PREFIX_LENGTH {
+      return Vec::new();
+    }
+    let mut candidates: Vec<&String> = self
+      .record_map
+      .keys()
+      .filter(|candidate| candidate.starts_with(id))
+      .collect();
+    candidates.sort();
+    candidates
+  }
+
   fn delete(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
     debug!("delete arguments: {:?}", args);
-    if args.len() == 0 {
+    if args.is_empty() {
       return DBResponse::Invalid("DELETE requires a key".to_string());
     }
-    let selected_record = args[0];
-    let record_value = self.record_map.get(selected_record).unwrap();
-    let content = record_value.content.clone();
-    let deleted_record = Record {
-      id: selected_record.to_string(),
-      action: ACTION_DELETE.to_string(),
-      content: "-".to_string(),
+    if let Some(message) = self.write_blocked() {
+      return DBResponse::Invalid(message);
+    }
+    let selected_record = match self.resolve_id(args[0]) {
+      Ok(id) => id,
+      Err(message) => return DBResponse::Invalid(message),
     };
-    self.append_data(&deleted_record);
-    self
-      .record_map
-      .insert(deleted_record.id.clone(), deleted_record.clone());
+    let content = self.record_map[&selected_record].content().to_string();
+    let deleted_record = self.next_record(&selected_record, ACTION_DELETE, "-");
+    if let Err(message) = self.append_data(&deleted_record) {
+      return DBResponse::Error(message);
+    }
+    self.apply_written(deleted_record);
+    self.snapshot_if_due();
     let message = format!("\"{}\" deleted", content);
-    return DBResponse::ROk(message.to_string());
+    DBResponse::ROk(message.to_string())
   }
 
   fn update(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
     if args.len() < 2 {
       return DBResponse::Invalid("UPDATE requires a key and an updated value".to_string());
     }
-    let selected_record = args[0];
-    let content = args[1..].join(" ");
-    let record_value = self.record_map.get(selected_record).unwrap();
-    let original_content = record_value.content.to_string();
-    let updated_record = Record {
-      id: selected_record.to_string(),
-      action: ACTION_UPDATE.to_string(),
-      content: content.to_string(),
+    if let Some(message) = self.write_blocked() {
+      return DBResponse::Invalid(message);
+    }
+    let selected_record = match self.resolve_id(args[0]) {
+      Ok(id) => id,
+      Err(message) => return DBResponse::Invalid(message),
     };
-    self.append_data(&updated_record);
-    self
-      .record_map
-      .insert(updated_record.id.clone(), updated_record.clone());
+    let content = args[1..].join(" ");
+    if let Some(message) = self.reject_content(&content) {
+      return DBResponse::Invalid(message);
+    }
+    let original_content = self.record_map[&selected_record].content().to_string();
+    let updated_record = self.next_record(&selected_record, ACTION_UPDATE, &content);
+    if let Err(message) = self.append_data(&updated_record) {
+      return DBResponse::Error(message);
+    }
+    self.apply_written(updated_record);
+    self.snapshot_if_due();
     let message = format!("\"{}\" updated to \"{}\"", original_content, content);
     DBResponse::ROk(message)
   }
 
-  fn list(&self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+  /// Lists the live records in list order, or filtered, sorted and paged as
+  /// the query after `LIST` asks. `LIST DETAILS` prefixes each record's
+  /// content with the time it was last written and its sequence number.
+  fn list(&self, query: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let query = match ListQuery::parse(query) {
+      Ok(query) => query,
+      Err(message) => return DBResponse::Invalid(message),
+    };
+    let mut entries = self.ordered_entries();
+    if let Some(filter) = &query.filter {
+      entries.retain(|entry| filter.is_match(entry.content()));
+    }
+    if let Some((key, descending)) = query.order {
+      entries.sort_by(|a, b| {
+        let ordering = match key {
+          SortKey::Content => a.content().cmp(b.content()),
+          SortKey::Created => a.added.seq.cmp(&b.added.seq),
+          SortKey::Updated => a.latest.seq.cmp(&b.latest.seq),
+        };
+        if descending {
+          ordering.reverse()
+        } else {
+          ordering
+        }
+      });
+    }
+    let page = entries
+      .into_iter()
+      .skip(query.offset)
+      .take(query.limit.unwrap_or(usize::MAX));
     let mut list: Vec<(String, String)> = Vec::new();
-    for record in self.record_map.values() {
-      if record.action != ACTION_DELETE {
-        list.push((record.id.to_string(), record.content.to_string()));
+    for entry in page {
+      let record = &entry.latest;
+      let content = if query.details {
+        format!(
+          "{} #{} {}",
+          display_time(&record.timestamp),
+          record.seq,
+          entry.content()
+        )
+      } else {
+        entry.content().to_string()
+      };
+      list.push((record.id.to_string(), content));
+    }
+    DBResponse::Data(list)
+  }
+
+  /// Lists the records of a `JSON` topic whose content matches a condition
+  /// on one of its fields, in list order. A condition of equality on an
+  /// indexed field is answered from the index.
+  fn find(&self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if self.format.content != Content::Json {
+      let message = format!(
+        "Topic {} does not hold JSON documents. FIND requires a topic created as JSON.",
+        self.id
+      );
+      return DBResponse::Invalid(message);
+    }
+    let condition = match args.split_first() {
+      Some((keyword, tokens)) if keyword.eq_ignore_ascii_case("WHERE") => {
+        match Condition::parse(tokens) {
+          Ok(condition) => condition,
+          Err(message) => return DBResponse::Invalid(message),
+        }
       }
+      _ => return DBResponse::Invalid("FIND requires a WHERE condition".to_string()),
+    };
+    let index = match condition.operator {
+      Operator::Equal => self.indexes.on(&condition.field),
+      _ => None,
+    };
+    let entries: Vec<&Entry> = match index {
+      Some(index) => {
+        let mut entries: Vec<&Entry> = index
+          .lookup(&condition.value)
+          .into_iter()
+          .filter_map(|id| self.record_map.get(id))
+          .collect();
+        entries.sort_by(|a, b| a.position.cmp(&b.position));
+        entries
+      }
+      None => self
+        .ordered_entries()
+        .into_iter()
+        .filter(|entry| {
+          documents::parse(entry.content()).is_ok_and(|document| condition.matches(&document))
+        })
+        .collect(),
+    };
+    let list: Vec<(String, String)> = entries
+      .iter()
+      .map(|entry| (entry.latest.id.to_string(), entry.content().to_string()))
+      .collect();
+    DBResponse::Data(list)
+  }
+
+  /// Lists the records holding any of the terms searched for, best match
+  /// first.
+  fn search(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let terms = search::terms(&args.join(" "));
+    if terms.is_empty() {
+      return DBResponse::Invalid("SEARCH requires terms to search for".to_string());
     }
+    let mut results = self.search_results(&terms);
+    results.rank();
+    let list: Vec<(String, String)> = results
+      .hits
+      .into_iter()
+      .map(|hit| (hit.id, hit.content))
+      .collect();
     DBResponse::Data(list)
   }
 
-  fn refresh(&mut self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    self.record_map.clear();
+  /// Records holding any of the terms, not yet ranked. The search index is
+  /// built if the topic has not been searched before.
+  fn search_results(&mut self, terms: &[String]) -> SearchResults {
+    if self.search_index.is_none() {
+      let mut search_index = SearchIndex::new(self.format.content);
+      for (id, entry) in &self.record_map {
+        search_index.insert(id, entry.content());
+      }
+      self.search_index = Some(search_index);
+    }
+    let search_index = self
+      .search_index
+      .as_ref()
+      .expect("search index was just built");
+    search_index.search(&self.id, terms, |id| {
+      self.record_map.get(id).map(|entry| entry.content())
+    })
+  }
+
+  /// Creates an index on a field of the records of a `JSON` topic.
+  fn create_index(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let (name, field) = match args {
+      [object, name, on, field]
+        if object.eq_ignore_ascii_case("INDEX") && on.eq_ignore_ascii_case("ON") =>
+      {
+        (*name, *field)
+      }
+      _ => return DBResponse::Invalid("CREATE INDEX requires a name, ON and a field".to_string()),
+    };
+    if self.format.content != Content::Json {
+      let message = format!(
+        "Topic {} does not hold JSON documents. An index requires a topic created as JSON.",
+        self.id
+      );
+      return DBResponse::Invalid(message);
+    }
+    if let Some(message) = self.write_blocked() {
+      return DBResponse::Invalid(message);
+    }
+    if !name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+      return DBResponse::Invalid(format!(
+        "Invalid index name {}. (letters, digits, - and _ only)",
+        name
+      ));
+    }
+    if self.indexes.named(name).is_some() {
+      return DBResponse::Invalid(format!("Index {} already exists.", name));
+    }
+    if let Some(index) = self.indexes.on(field) {
+      return DBResponse::Invalid(format!(
+        "Field {} is already indexed by {}.",
+        field, index.name
+      ));
+    }
+    self.indexes.indexes.push(Index::new(name, field));
+    if let Err(message) = self.write_indexes() {
+      self.indexes.indexes.pop();
+      return DBResponse::Error(message);
+    }
+    self.rebuild_indexes();
+    DBResponse::ROk(format!("Index {} created.", name))
+  }
+
+  fn drop_index(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let name = match args {
+      [object, name] if object.eq_ignore_ascii_case("INDEX") => *name,
+      _ => return DBResponse::Invalid("DROP INDEX requires a name".to_string()),
+    };
+    if let Some(message) = self.write_blocked() {
+      return DBResponse::Invalid(message);
+    }
+    let position = match self
+      .indexes
+      .indexes
+      .iter()
+      .position(|index| index.name == name)
+    {
+      Some(position) => position,
+      None => return DBResponse::Invalid(format!("Index {} does not exist.", name)),
+    };
+    let index = self.indexes.indexes.remove(position);
+    if let Err(message) = self.write_indexes() {
+      self.indexes.indexes.insert(position, index);
+      return DBResponse::Error(message);
+    }
+    DBResponse::ROk(format!("Index {} dropped.", name))
+  }
+
+  fn write_indexes(&self) -> Result<(), String> {
+    self
+      .indexes
+      .write(&self.storage.index_path())
+      .map_err(|error| {
+        format!(
+          "Unable to write the indexes of topic {}: {}",
+          self.id, error
+        )
+      })
+  }
+
+  /// Lists the fields of the topic's schema with their types, or the
+  /// topic's indexes with the field each is on.
+  fn show(&self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    match args.first().map(|object| object.to_uppercase()).as_deref() {
+      Some("SCHEMA") => (),
+      Some("INDEXES") => return self.show_indexes(),
+      _ => return DBResponse::Invalid("SHOW requires SCHEMA or INDEXES".to_string()),
+    }
+    if self.schema.fields.is_empty() {
+      return DBResponse::ROk(format!("Topic {} has no schema.", self.id));
+    }
+    let items: Vec<(String, String)> = self
+      .schema
+      .fields
+      .iter()
+      .map(|field| (field.name.to_string(), field.describe()))
+      .collect();
+    DBResponse::Data(items)
+  }
+
+  fn show_indexes(&self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if self.indexes.is_empty() {
+      return DBResponse::ROk(format!("Topic {} has no indexes.", self.id));
+    }
+    let items: Vec<(String, String)> = self
+      .indexes
+      .indexes
+      .iter()
+      .map(|index| {
+        let description = format!("ON {} ({} values)", index.field, index.values());
+        (index.name.to_string(), description)
+      })
+      .collect();
+    DBResponse::Data(items)
+  }
+
+  /// Adds a field to the topic's schema, changes one, or drops one. Records
+  /// already written are left as they are; the number of live records that
+  /// do not conform to the new schema is reported.
+  fn alter(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let args = match args.split_first() {
+      Some((object, args)) if object.eq_ignore_ascii_case("SCHEMA") => args,
+      _ => return DBResponse::Invalid("ALTER requires SCHEMA".to_string()),
+    };
+    if args.len() < 2 {
+      return DBResponse::Invalid(
+        "ALTER SCHEMA requires ADD, MODIFY or DROP and a field".to_string(),
+      );
+    }
+    if self.format.content != Content::Json {
+      let message = format!(
+        "Topic {} does not hold JSON documents. A schema requires a topic created as JSON.",
+        self.id
+      );
+      return DBResponse::Invalid(message);
+    }
+    if let Some(message) = self.write_blocked() {
+      return DBResponse::Invalid(message);
+    }
+    let mut schema = self.schema.clone();
+    let name = args[1];
+    let existing = schema.fields.iter().position(|field| field.name == name);
+    match (args[0].to_uppercase().as_str(), existing) {
+      ("ADD", Some(_)) => {
+        return DBResponse::Invalid(format!("Field {} is already in the schema.", name))
+      }
+      ("MODIFY" | "DROP", None) => {
+        return DBResponse::Invalid(format!("Field {} is not in the schema.", name))
+      }
+      ("ADD", None) => match Field::parse(&args[1..]) {
+        Ok(field) => schema.fields.push(field),
+        Err(message) => return DBResponse::Invalid(message),
+      },
+      ("MODIFY", Some(index)) => match Field::parse(&args[1..]) {
+        Ok(field) => schema.fields[index] = field,
+        Err(message) => return DBResponse::Invalid(message),
+      },
+      ("DROP", Some(index)) if args.len() == 2 => {
+        schema.fields.remove(index);
+      }
+      ("DROP", Some(_)) => {
+        return DBResponse::Invalid("ALTER SCHEMA DROP takes only a field".to_string())
+      }
+      (other, _) => {
+        let message = format!(
+          "Unknown ALTER SCHEMA option {}. (expected ADD, MODIFY or DROP)",
+          other
+        );
+        return DBResponse::Invalid(message);
+      }
+    }
+    if let Err(error) = schema.write(&self.storage.schema_path()) {
+      return DBResponse::Error(format!(
+        "Unable to write the schema of topic {}: {}",
+        self.id, error
+      ));
+    }
+    self.schema = schema;
+    let nonconforming = self
+      .record_map
+      .values()
+      .filter(|entry| self.schema.reject(entry.content()).is_some())
+      .count();
+    let message = if nonconforming == 0 {
+      format!("Schema of topic {} altered.", self.id)
+    } else {
+      format!(
+        "Schema of topic {} altered. {} record(s) do not conform to it.",
+        self.id, nonconforming
+      )
+    };
+    DBResponse::ROk(message)
+  }
+
+  fn info(&self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if args.is_empty() {
+      return DBResponse::Invalid("INFO requires a key".to_string());
+    }
+    let entry = match self.resolve_id(args[0]) {
+      Ok(id) => &self.record_map[&id],
+      Err(message) => return DBResponse::Invalid(message),
+    };
+    let mut items: Vec<(String, String)> = Vec::new();
+    let id = format!("record.id: {}", entry.latest.id);
+    items.push(("".to_string(), id));
+    let sequence = format!("record.sequence: {}", entry.latest.seq);
+    items.push(("".to_string(), sequence));
+    let created = format!("record.created: {}", display_time(&entry.added.timestamp));
+    items.push(("".to_string(), created));
+    let updated = format!("record.updated: {}", display_time(&entry.latest.timestamp));
+    items.push(("".to_string(), updated));
+    let content = format!("record.content: {}", entry.content());
+    items.push(("".to_string(), content));
+    let index = self
+      .ordered_entries()
+      .iter()
+      .position(|listed| listed.added.id == entry.added.id)
+      .unwrap_or_default();
+    let position = format!("record.position: {}", index + 1);
+    items.push(("".to_string(), position));
+    DBResponse::Data(items)
+  }
+
+  /// Lists every record written for an id, oldest first, including those
+  /// that were superseded or deleted. The id of a deleted record has to be
+  /// given in full.
+  fn history(&mut self, args: &[&str]) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if args.is_empty() {
+      return DBResponse::Invalid("HISTORY requires a key".to_string());
+    }
+    let selected_record = match self.resolve_id(args[0]) {
+      Ok(id) => id,
+      Err(_) if !args[0].is_empty() && self.prefix_candidates(args[0]).is_empty() => {
+        args[0].to_string()
+      }
+      Err(message) => return DBResponse::Invalid(message),
+    };
+    if let Err(message) = self.flush_pending() {
+      return DBResponse::Error(message);
+    }
+    let files = match self.log_files() {
+      Ok(files) => files,
+      Err(message) => return DBResponse::Error(message),
+    };
+    let mut items: Vec<(String, String)> = Vec::new();
+    // Where each record is in the list as the log is read, to show where
+    // in the list a move put the record.
+    let mut positions: HashMap<String, Position> = HashMap::new();
+    let mut numbered: u64 = 0;
+    for file in files {
+      let mut reader = match self.open_file(&file, 0) {
+        Ok(reader) => reader,
+        Err(message) => return DBResponse::Error(message),
+      };
+      loop {
+        let record = match self.read_record(&mut reader, numbered) {
+          Ok(Some(record)) => record,
+          Ok(None) => break,
+          Err(message) => return DBResponse::Error(message),
+        };
+        if record.id == selected_record {
+          items.push(history_item(&record, &positions));
+        }
+        track_position(&mut positions, &record);
+      }
+      numbered += reader.count;
+    }
+    if items.is_empty() {
+      return DBResponse::Invalid(format!("Record {} has no history.", selected_record));
+    }
+    DBResponse::Data(items)
+  }
+
+  fn refresh(&mut self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    match self.load() {
+      Ok(_) => DBResponse::ROk("Topic refreshed.".to_string()),
+      Err(message) => DBResponse::Error(message),
+    }
+  }
+
+  fn status(&self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let mut items: Vec<(String, String)> = Vec::new();
+    let format = format!("topic.format: {}", self.format.version);
+    items.push(("".to_string(), format));
+    let encoding = format!("topic.encoding: {}", self.format.encoding.name());
+    items.push(("".to_string(), encoding));
+    let content = format!("topic.content: {}", self.format.content.name());
+    items.push(("".to_string(), content));
+    let durability = format!("topic.durability: {}", self.config.durability.name());
+    items.push(("".to_string(), durability));
+    let records = format!("topic.records: {}", self.record_map.len());
+    items.push(("".to_string(), records));
+    let log_records = format!("topic.log_records: {}", self.total_records);
+    items.push(("".to_string(), log_records));
+    let dead_records = self.total_records.saturating_sub(self.live_records());
+    let dead_records = format!("topic.dead_records: {}", dead_records);
+    items.push(("".to_string(), dead_records));
+    if let Some(as_of) = self.as_of {
+      let point = format!("topic.as_of: {}", as_of.describe());
+      items.push(("".to_string(), point));
+    }
+    if let Some((offset, length)) = self.damaged_tail {
+      let damage = format!("topic.damaged_tail: {} bytes at byte {}", length, offset);
+      items.push(("".to_string(), damage));
+    }
+    DBResponse::Data(items)
+  }
+
+  /// Rewrites the topic's files with only the records of live entries: the
+  /// record that added each entry and, if it has changed since, its latest
+  /// update. Deleted entries and superseded updates are dropped. When the
+  /// records holding the highest sequence numbers are dropped, the highest
+  /// number is recorded in the header of the last file. Each file
+  /// is compacted on its own and only if it has something to drop; the
+  /// original is kept as a backup. A segment left with no records is
+  /// removed unless it is the last one.
+  fn compact(&mut self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let time_stamp: DateTime<Local> = Local::now();
+    let stamp = time_stamp.format(BACKUP_STAMP).to_string();
+    let mut keep: HashSet<u64> = HashSet::new();
+    for entry in self.record_map.values() {
+      keep.extend(entry.records().iter().map(|record| record.seq));
+    }
+    if let Err(message) = self.release_writer() {
+      return DBResponse::Error(message);
+    }
+    let files = match self.log_files() {
+      Ok(files) => files,
+      Err(message) => return DBResponse::Error(message),
+    };
+    if let Err(error) = self.storage.remove_snapshot() {
+      return DBResponse::Error(format!(
+        "Unable to remove the snapshot of topic {}: {}",
+        self.id, error
+      ));
+    }
+    let file_count = files.len();
+    let mut rewritten = 0;
+    let mut numbered: u64 = 0;
+    for (index, file) in files.iter().enumerate() {
+      let first_number = numbered;
+      let mut reader = match self.open_file(file, 0) {
+        Ok(reader) => reader,
+        Err(message) => return DBResponse::Error(message),
+      };
+      let mut kept = 0;
+      loop {
+        match self.read_record(&mut reader, first_number) {
+          Ok(Some(record)) if keep.contains(&record.seq) => kept += 1,
+          Ok(Some(_)) => (),
+          Ok(None) => break,
+          Err(message) => return DBResponse::Error(message),
+        }
+      }
+      numbered += reader.count;
+      let loses_last_seq = index + 1 == file_count
+        && reader.format.has_stamps()
+        && !keep.contains(&self.last_seq)
+        && reader.format.prior_seq < self.last_seq;
+      if kept == reader.count
+        && reader.corrupt.is_empty()
+        && !reader.has_damaged_tail()
+        && !loses_last_seq
+      {
+        continue;
+      }
+      drop(reader);
+      let backup = backup_path(file, &stamp);
+      if fs::rename(file, &backup).is_err() {
+        return DBResponse::Error(
+          "An error occured while backing up the original file.".to_string(),
+        );
+      }
+      rewritten += 1;
+      if kept == 0 && index + 1 < file_count {
+        continue;
+      }
+      let prior_seq = if loses_last_seq { self.last_seq } else { 0 };
+      if let Err(message) = self.write_kept(&backup, file, &keep, first_number, prior_seq) {
+        return DBResponse::Error(message);
+      }
+    }
+    if let Err(message) = self.load() {
+      return DBResponse::Error(message);
+    }
+    if self.storage.is_segmented() {
+      let message = format!(
+        "Topic compacted. {} of {} segments rewritten.",
+        rewritten, file_count
+      );
+      return DBResponse::ROk(message);
+    }
+    DBResponse::ROk("Topic compacted.".to_string())
+  }
+
+  /// Copies the records in `keep` from one of the topic's files (read from
+  /// its backup) into a new file in the same format, recording `prior_seq`
+  /// in its header if it is higher than the one already there.
+  fn write_kept(
+    &self,
+    source: &Path,
+    file: &Path,
+    keep: &HashSet<u64>,
+    numbered: u64,
+    prior_seq: u64,
+  ) -> Result<(), String> {
+    let failed = |_| "An error occured while writing the compacted file.".to_string();
+    let mut reader = self.open_file(source, 0)?;
+    let format = Format {
+      prior_seq: reader.format.prior_seq.max(prior_seq),
+      ..reader.format
+    };
+    let mut writer = BufWriter::new(File::create(file).map_err(failed)?);
+    writer.write_all(&format.header()).map_err(failed)?;
+    while let Some(record) = self.read_record(&mut reader, numbered)? {
+      if keep.contains(&record.seq) {
+        writer
+          .write_all(&reader.format.encode(&record))
+          .map_err(failed)?;
+      }
+    }
+    writer.flush().map_err(failed)?;
+    if self.config.durability != Durability::None {
+      writer.get_ref().sync_data().map_err(failed)?;
+    }
+    Ok(())
+  }
+}
+
+/// Position for a record placed at `index` of `entries`, the list it is
+/// placed in.
+fn position_at(entries: &[&Entry], index: usize) -> Position {
+  let before = index
+    .checked_sub(1)
+    .and_then(|before| entries.get(before))
+    .map(|entry| &entry.position);
+  let after = entries.get(index).map(|entry| &entry.position);
+  Position::between(before, after)
+}
+
+/// Row of `HISTORY` output for one record, given where the other records
+/// were in the list when it was written. An `INSERT` is shown with its
+/// content alone and a `MOVE` with the place in the list, counted from 1,
+/// it moved the record to.
+fn history_item(record: &Record, positions: &HashMap<String, Position>) -> (String, String) {
+  let detail = match record.action.as_str() {
+    ACTION_DELETE => None,
+    ACTION_INSERT => match positions::split_insert(&record.content) {
+      Ok((_, content)) => Some(content.to_string()),
+      Err(_) => Some(record.content.to_string()),
+    },
+    ACTION_MOVE => match Position::parse(&record.content) {
+      Ok(position) => {
+        let before = positions
+          .iter()
+          .filter(|(id, other)| **id != record.id && **other < position)
+          .count();
+        Some(format!("TO {}", before + 1))
+      }
+      Err(_) => Some(record.content.to_string()),
+    },
+    _ => Some(record.content.to_string()),
+  };
+  let version = match detail {
+    Some(detail) => format!(
+      "{} {} {}",
+      display_time(&record.timestamp),
+      action_name(&record.action),
+      detail
+    ),
+    None => format!(
+      "{} {}",
+      display_time(&record.timestamp),
+      action_name(&record.action)
+    ),
+  };
+  (format!("#{}", record.seq), version)
+}
+
+/// Updates where the records are in the list for a record read from the
+/// log, the way `Topic::apply` places the live entries.
+fn track_position(positions: &mut HashMap<String, Position>, record: &Record) {
+  match record.action.as_str() {
+    ACTION_DELETE => {
+      positions.remove(&record.id);
+    }
+    ACTION_MOVE => {
+      if let (Ok(moved), Some(position)) = (
+        Position::parse(&record.content),
+        positions.get_mut(&record.id),
+      ) {
+        *position = moved;
+      }
+    }
+    _ if positions.contains_key(&record.id) => (),
+    ACTION_INSERT => {
+      if let Ok((position, _)) = positions::split_insert(&record.content) {
+        positions.insert(record.id.to_string(), position);
+      }
+    }
+    _ => {
+      positions.insert(record.id.to_string(), Position::added(record.seq));
+    }
+  }
+}
+
+fn action_name(action: &str) -> &str {
+  match action {
+    ACTION_ADD => "ADD",
+    ACTION_UPDATE => "UPDATE",
+    ACTION_DELETE => "DELETE",
+    ACTION_INSERT => "INSERT",
+    ACTION_MOVE => "MOVE",
+    _ => action,
+  }
+}
 
-    let records = Topic::get_records(&self.path);
-    for record in records {
-      if record.action == ACTION_DELETE {
-        if self.record_map.contains_key(&record.id) {
-          self.record_map.remove(&record.id);
-        }
-      } else {
-        self.record_map.insert(record.id.clone(), record.clone());
+/// Applies the configured backup retention to a topic, returning the number
+/// of backups removed.
+fn remove_expired_backups(storage: &Storage, config: &EngineConfig) -> usize {
+  let keep_count = config.backup_keep_count.unwrap_or(usize::MAX);
+  let oldest = config
+    .backup_max_age
+    .and_then(|age| chrono::Duration::from_std(age).ok())
+    .map(|age| Local::now().naive_local() - age);
+  let mut removed = 0;
+  for (index, (taken, paths)) in storage.backups().iter().enumerate() {
+    let expired = index >= keep_count || oldest.is_some_and(|oldest| *taken < oldest);
+    if !expired {
+      continue;
+    }
+    for path in paths {
+      if let Err(error) = fs::remove_file(path) {
+        warn!("unable to remove backup {}: {}", path.display(), error);
       }
     }
-    DBResponse::ROk("Topic refreshed.".to_string())
+    removed += 1;
   }
+  removed
+}
 
-  fn compact(&mut self) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    let time_stamp: DateTime<Local> = Local::now();
-    let move_path = format!("{}.bkp_{}", self.path, time_stamp.format("%Y%m%d_%H%M%S%f"));
-    match fs::rename(&self.path, &move_path) {
-      Ok(_) => match File::create(&self.path) {
-        Ok(_) => {
-          for record in self.record_map.values() {
-            self.append_data(&record);
-          }
-          self.refresh();
-          DBResponse::ROk("Topic compacted.".to_string())
-        }
-        Err(_) => {
-          DBResponse::Error("An error occured while backing up the original file.".to_string())
-        }
-      },
-      Err(_) => {
-        DBResponse::Error("An error occured while backing up the original file.".to_string())
-      }
+/// Formats a record timestamp in local time, or "-" for records written in
+/// a format without timestamps.
+fn display_time(timestamp: &Option<DateTime<Utc>>) -> String {
+  match timestamp {
+    Some(time) => time
+      .with_timezone(&Local)
+      .to_rfc3339_opts(SecondsFormat::Millis, false),
+    None => "-".to_string(),
+  }
+}
+
+impl Drop for Topic {
+  fn drop(&mut self) {
+    if let Err(message) = self.flush_pending() {
+      warn!("{}", message);
     }
   }
 }
@@ -188,16 +1755,46 @@ impl ContextProcess for Topic {
     self.id.to_string()
   }
 
+  /// Syncs the writes made since the last sync once the durability
+  /// interval has passed.
+  fn maintain(&mut self) {
+    let overdue = match self.config.durability {
+      Durability::Interval(interval) => self.unsynced && self.last_sync.elapsed() >= interval,
+      _ => false,
+    };
+    if overdue {
+      if let Err(message) = self.flush_pending() {
+        warn!("{}", message);
+      }
+    }
+  }
+
   fn process(&mut self, request: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
     let command_line: Vec<&str> = request.split(' ').collect();
     let command: &str = &command_line[0].to_string().trim().to_uppercase();
     match command {
-      "CLOSE" => DBResponse::CloseContext,
+      "CLOSE" => {
+        self.auto_compact();
+        DBResponse::CloseContext
+      }
       "ADD" => self.add(&command_line[1..]),
       "DELETE" => self.delete(&command_line[1..]),
       "UPDATE" => self.update(&command_line[1..]),
-      "LIST" => self.list(),
+      "INSERT" => self.insert(&command_line[1..]),
+      "PUT" => self.put(&command_line[1..]),
+      "GET" => self.get(&command_line[1..]),
+      "MOVE" => self.move_record(&command_line[1..]),
+      "LIST" => self.list(request.split_once(' ').map_or("", |(_, query)| query)),
+      "FIND" => self.find(&command_line[1..]),
+      "SEARCH" => self.search(&command_line[1..]),
+      "INFO" => self.info(&command_line[1..]),
+      "HISTORY" => self.history(&command_line[1..]),
       "REFRESH" => self.refresh(),
+      "STATUS" => self.status(),
+      "SHOW" => self.show(&command_line[1..]),
+      "ALTER" => self.alter(&command_line[1..]),
+      "CREATE" => self.create_index(&command_line[1..]),
+      "DROP" => self.drop_index(&command_line[1..]),
       _ => DBResponse::Unknown(command.to_string()),
     }
   }
@@ -208,25 +1805,450 @@ pub struct TopicController {
   /// Location of the database
   pub db_home: String,
   pub relative_path: String,
+  config: EngineConfig,
 }
 
 impl TopicController {
-  pub fn new(db_home: &str, relative_path: &str) -> TopicController {
+  pub fn new(db_home: &str, relative_path: &str, config: &EngineConfig) -> TopicController {
     TopicController {
       db_home: db_home.to_string(),
       relative_path: relative_path.to_string(),
+      config: config.clone(),
     }
   }
 
-  fn topic_path(&self, topic_id: &str) -> String {
-    let path = format!("{}{}{}.tpc", self.db_home, self.relative_path, topic_id);
+  /// Path of the topic without the extension of its file or directory.
+  fn topic_base(&self, topic_id: &str) -> String {
+    let path = format!("{}{}{}", self.db_home, self.relative_path, topic_id);
     debug!("topic path = {}", path);
     path
   }
 
-  fn topic_exists(&self, topic_id: &str) -> bool {
-    let topic_path = self.topic_path(topic_id);
-    Path::new(&topic_path).exists()
+  fn storage(&self, topic_id: &str) -> Option<Storage> {
+    Storage::find(&self.topic_base(topic_id))
+  }
+
+  /// Migrates a topic's files to the current format, removing its snapshot
+  /// if any file was rewritten.
+  fn migrate_topic(&self, topic_id: &str, stamp: &str) -> Result<Migration, String> {
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => return Err(format!("{} does not exist.", topic_id)),
+    };
+    let migration = migrate::migrate(&storage, stamp)?;
+    if migration.files > 0 {
+      if let Err(error) = storage.remove_snapshot() {
+        warn!("topic {}: unable to remove snapshot: {}", topic_id, error);
+      }
+    }
+    Ok(migration)
+  }
+
+  /// Ids of the topics in the controller's directory, in name order.
+  fn topic_ids(&self) -> io::Result<Vec<String>> {
+    let current_dir = format!("{}{}", self.db_home, self.relative_path);
+    let mut topic_ids: Vec<String> = Vec::new();
+    for file in fs::read_dir(current_dir)? {
+      let path = file?.path();
+      let topic_type = match path.extension() {
+        Some(value) => value.to_string_lossy(),
+        _ => continue,
+      };
+      if topic_type == TOPIC_EXTENSION || (topic_type == SEGMENTED_EXTENSION && path.is_dir()) {
+        let topic_name = path.file_stem().unwrap_or_default().to_string_lossy();
+        topic_ids.push(topic_name.to_string());
+      }
+    }
+    topic_ids.sort();
+    Ok(topic_ids)
+  }
+
+  /// Lists a topic's compaction backups, newest first, keyed by the time
+  /// stamp `RESTORE TOPIC ... FROM` takes.
+  pub fn list_backups(&self, topic_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("{} does not exist.", topic_id);
+        return DBResponse::Invalid(message);
+      }
+    };
+    let mut items: Vec<(String, String)> = Vec::new();
+    for (taken, paths) in storage.backups() {
+      let size: u64 = paths
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+      let description = format!(
+        "{} {} file(s), {} bytes",
+        taken.format("%Y-%m-%d %H:%M:%S"),
+        paths.len(),
+        size
+      );
+      items.push((taken.format(BACKUP_STAMP).to_string(), description));
+    }
+    DBResponse::Data(items)
+  }
+
+  /// Rolls a topic back to how it was before a compaction, using the newest
+  /// backup or the one whose time stamp starts with the text after `FROM`.
+  /// Each file is restored from the oldest backup taken at or after that
+  /// compaction, so later compactions are undone as well. A segment is
+  /// removed if that backup is the marker of it missing; the file of a
+  /// single file topic is never removed. The files being replaced are
+  /// themselves kept as a backup, along with a marker for each segment the
+  /// restore brings back that was missing, so a restore can be undone the
+  /// same way. Every backup is copied next to its file before any file is
+  /// replaced, and each copy is renamed over the file it restores.
+  pub fn restore(&self, args: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let tokens: Vec<&str> = args.split_whitespace().collect();
+    let topic_id = match tokens.first() {
+      Some(topic_id) => *topic_id,
+      None => return DBResponse::Invalid("Restore requires an id".to_string()),
+    };
+    let from = match &tokens[1..] {
+      [] => None,
+      [keyword, stamp] if keyword.eq_ignore_ascii_case("FROM") => Some(*stamp),
+      _ => {
+        return DBResponse::Invalid("Expected RESTORE TOPIC <id> [FROM <timestamp>]".to_string())
+      }
+    };
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("{} does not exist.", topic_id);
+        return DBResponse::Invalid(message);
+      }
+    };
+    let backups = storage.backups();
+    let chosen = match from {
+      None => backups.first(),
+      Some(stamp) => {
+        let matching: Vec<_> = backups
+          .iter()
+          .filter(|(taken, _)| taken.format(BACKUP_STAMP).to_string().starts_with(stamp))
+          .collect();
+        if matching.len() > 1 {
+          let message = format!(
+            "{} matches {} backups of topic {}.",
+            stamp,
+            matching.len(),
+            topic_id
+          );
+          return DBResponse::Invalid(message);
+        }
+        matching.first().copied()
+      }
+    };
+    let restored_at = match chosen {
+      Some((taken, _)) => *taken,
+      None => {
+        let message = match from {
+          Some(stamp) => format!("No backup of topic {} matches {}.", topic_id, stamp),
+          None => format!("Topic {} has no backups.", topic_id),
+        };
+        return DBResponse::Invalid(message);
+      }
+    };
+    let mut sources: HashMap<PathBuf, PathBuf> = HashMap::new();
+    for (_, paths) in backups.iter().filter(|(taken, _)| *taken >= restored_at) {
+      for path in paths {
+        if let Some(source) = backup_source(path) {
+          sources.insert(source, path.clone());
+        }
+      }
+    }
+    // Each file with the copy of its backup to rename over it, or `None`
+    // for a segment to remove.
+    let mut restoring: Vec<(&PathBuf, Option<PathBuf>)> = Vec::new();
+    let discard = |restoring: &[(&PathBuf, Option<PathBuf>)]| {
+      for temporary in restoring
+        .iter()
+        .filter_map(|(_, temporary)| temporary.as_ref())
+      {
+        let _ = fs::remove_file(temporary);
+      }
+    };
+    for (source, backup) in &sources {
+      if storage::is_missing_marker(backup) {
+        if storage.is_segmented() {
+          restoring.push((source, None));
+        }
+        continue;
+      }
+      let temporary = temporary_path(source, RESTORE_TEMPORARY);
+      restoring.push((source, Some(temporary.clone())));
+      if fs::copy(backup, &temporary).is_err() {
+        discard(&restoring);
+        return DBResponse::Error("An error occured while restoring the backup.".to_string());
+      }
+    }
+    let stamp = Local::now().format(BACKUP_STAMP).to_string();
+    let mut replaced = 0;
+    for index in 0..restoring.len() {
+      let (source, temporary) = &restoring[index];
+      let kept = match temporary {
+        Some(_) if source.exists() => fs::hard_link(source, backup_path(source, &stamp)),
+        Some(_) => File::create(storage::missing_marker_path(source, &stamp)).map(|_| ()),
+        None if source.exists() => fs::rename(source, backup_path(source, &stamp)),
+        None => continue,
+      };
+      if kept.is_err() {
+        discard(&restoring[index..]);
+        return DBResponse::Error(
+          "An error occured while backing up the current file.".to_string(),
+        );
+      }
+      replaced += 1;
+      if let Some(temporary) = temporary {
+        if fs::rename(temporary, source).is_err() {
+          discard(&restoring[index..]);
+          return DBResponse::Error("An error occured while restoring the backup.".to_string());
+        }
+      }
+    }
+    if let Err(error) = storage.remove_snapshot() {
+      warn!("topic {}: unable to remove snapshot: {}", topic_id, error);
+    }
+    let mut message = format!(
+      "Topic {} restored from backup {}.",
+      topic_id,
+      restored_at.format(BACKUP_STAMP)
+    );
+    if replaced > 0 {
+      message = format!("{} Replaced files kept as backup {}.", message, stamp);
+    }
+    DBResponse::ROk(message)
+  }
+
+  /// Opens a read-only view of the topic as it was at a sequence number or
+  /// point in time.
+  pub fn open_as_of(
+    &self,
+    topic_id: &str,
+    as_of: &str,
+  ) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("{} does not exist at {}.", topic_id, self.relative_path);
+        return DBResponse::Error(message);
+      }
+    };
+    let as_of = match AsOf::parse(as_of) {
+      Ok(as_of) => as_of,
+      Err(message) => return DBResponse::Invalid(message),
+    };
+    let topic = match Topic::new(topic_id, storage, &self.config, Some(as_of)) {
+      Ok(topic) => topic,
+      Err(message) => return DBResponse::Error(message),
+    };
+    let context_label = format!("{}[{} {}]", self.relative_path, topic_id, as_of.describe());
+    DBResponse::OpenContext((Box::new(topic), context_label))
+  }
+
+  /// Checks every record of a topic and lists the problems found, by line
+  /// (or frame) of the file they were found in.
+  pub fn verify(&self, topic_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("{} does not exist.", topic_id);
+        return DBResponse::Invalid(message);
+      }
+    };
+    let report = match verify::verify(&storage) {
+      Ok(report) => report,
+      Err(message) => {
+        return DBResponse::Error(format!("Unable to read topic {}: {}", topic_id, message))
+      }
+    };
+    if report.anomalies.is_empty() {
+      let message = format!(
+        "Topic {} verified. No problems found in {} records.",
+        topic_id, report.records
+      );
+      return DBResponse::ROk(message);
+    }
+    let mut items: Vec<(String, String)> = Vec::new();
+    for anomaly in report.anomalies {
+      let location = anomaly_location(&storage, &anomaly);
+      let problem = format!("{} (byte {})", anomaly.problem, anomaly.offset);
+      items.push((location, problem));
+    }
+    DBResponse::Data(items)
+  }
+
+  /// Rewrites the files of a topic that have problems, leaving out every
+  /// record `VERIFY TOPIC` reports. The originals are kept as a backup.
+  pub fn repair(&self, topic_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("{} does not exist.", topic_id);
+        return DBResponse::Invalid(message);
+      }
+    };
+    let stamp = Local::now().format(BACKUP_STAMP).to_string();
+    let report = match verify::repair(&storage, &stamp) {
+      Ok(report) => report,
+      Err(message) => {
+        return DBResponse::Error(format!("Unable to repair topic {}: {}", topic_id, message))
+      }
+    };
+    if report.anomalies.is_empty() {
+      return DBResponse::ROk(format!("Topic {} has no problems to repair.", topic_id));
+    }
+    if let Err(error) = storage.remove_snapshot() {
+      warn!("topic {}: unable to remove snapshot: {}", topic_id, error);
+    }
+    let message = format!(
+      "Topic {} repaired. {} problem(s) fixed, {} record(s) dropped. Original files kept as backup {}.",
+      topic_id,
+      report.anomalies.len(),
+      report.dropped,
+      stamp
+    );
+    DBResponse::ROk(message)
+  }
+
+  /// Verifies every topic in the directory, adding each to the report along
+  /// with any stray files inside segmented topics. An unreadable directory
+  /// is reported by its directory controller.
+  pub fn check(&self, report: &mut DatabaseReport) {
+    let topic_ids = match self.topic_ids() {
+      Ok(topic_ids) => topic_ids,
+      Err(_) => return,
+    };
+    for topic_id in topic_ids {
+      let mut topic_report = TopicReport {
+        path: format!("{}{}", self.relative_path, topic_id),
+        records: 0,
+        problems: Vec::new(),
+        error: None,
+      };
+      let storage = match self.storage(&topic_id) {
+        Some(storage) => storage,
+        None => continue,
+      };
+      match verify::verify(&storage) {
+        Ok(verified) => {
+          topic_report.records = verified.records;
+          for anomaly in verified.anomalies {
+            topic_report.problems.push(Problem {
+              location: anomaly_location(&storage, &anomaly),
+              offset: anomaly.offset,
+              description: anomaly.problem,
+            });
+          }
+        }
+        Err(message) => topic_report.error = Some(message),
+      }
+      match storage.stray_files() {
+        Ok(strays) => {
+          for (path, reason) in strays {
+            report.stray_files.push(StrayFile { path, reason });
+          }
+        }
+        Err(error) => report.stray_files.push(StrayFile {
+          path: PathBuf::from(self.topic_base(&topic_id)),
+          reason: format!("unreadable directory: {}", error),
+        }),
+      }
+      report.topics.push(topic_report);
+    }
+  }
+
+  /// Rewrites a topic written in an older format in the current one. The
+  /// original files are kept as a backup.
+  pub fn migrate(&self, topic_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
+    if self.storage(topic_id).is_none() {
+      return DBResponse::Invalid(format!("{} does not exist.", topic_id));
+    }
+    let stamp = Local::now().format(BACKUP_STAMP).to_string();
+    let migration = match self.migrate_topic(topic_id, &stamp) {
+      Ok(migration) => migration,
+      Err(message) => {
+        return DBResponse::Error(format!("Unable to migrate topic {}: {}", topic_id, message))
+      }
+    };
+    if migration.files == 0 {
+      let message = format!(
+        "Topic {} is already in format {}.",
+        topic_id, CURRENT_VERSION
+      );
+      return DBResponse::ROk(message);
+    }
+    let description =
+      FormatVersion::find(CURRENT_VERSION).map_or("", |version| version.description);
+    let message = format!(
+      "Topic {} migrated from format {} to {} ({}). {} record(s) rewritten. Original files kept as backup {}.",
+      topic_id, migration.from_version, CURRENT_VERSION, description, migration.records, stamp
+    );
+    DBResponse::ROk(message)
+  }
+
+  /// Migrates every topic in the directory, adding each to the report.
+  pub fn migrate_all(&self, stamp: &str, report: &mut MigrationReport) {
+    let topic_ids = match self.topic_ids() {
+      Ok(topic_ids) => topic_ids,
+      Err(error) => {
+        report
+          .topics
+          .push((self.relative_path.to_string(), Err(error.to_string())));
+        return;
+      }
+    };
+    for topic_id in topic_ids {
+      let path = format!("{}{}", self.relative_path, topic_id);
+      report
+        .topics
+        .push((path, self.migrate_topic(&topic_id, stamp)));
+    }
+  }
+
+  /// Searches every topic in the directory, adding the records found and
+  /// the statistics of the records searched to `results`. Topics are opened
+  /// read-only, and one that cannot be opened is skipped.
+  pub fn search(&self, terms: &[String], results: &mut SearchResults) {
+    let topic_ids = match self.topic_ids() {
+      Ok(topic_ids) => topic_ids,
+      Err(_) => return,
+    };
+    for topic_id in topic_ids {
+      let storage = match self.storage(&topic_id) {
+        Some(storage) => storage,
+        None => continue,
+      };
+      let mut topic = match Topic::read_only(&topic_id, storage, &self.config) {
+        Ok(topic) => topic,
+        Err(message) => {
+          warn!("search skipped topic {}: {}", topic_id, message);
+          continue;
+        }
+      };
+      let mut found = topic.search_results(terms);
+      for hit in found.hits.iter_mut() {
+        hit.topic = format!("{}{}", self.relative_path, topic_id);
+      }
+      results.extend(found);
+    }
+  }
+}
+
+/// Where in a topic a problem was found, naming the segment for segmented
+/// topics.
+fn anomaly_location(storage: &Storage, anomaly: &verify::Anomaly) -> String {
+  if storage.is_segmented() {
+    let segment = anomaly
+      .file
+      .file_name()
+      .unwrap_or_default()
+      .to_string_lossy();
+    format!("{} {}", segment, anomaly.location)
+  } else {
+    anomaly.location.to_string()
   }
 }
 
@@ -238,12 +2260,43 @@ impl ContextController for TopicController {
   ///
   /// * `args` - List of arguments for topic creation.
   /// - topic_id (required) Id of the topic to be created.
-  fn create(&self, topic_id: &str) -> Result<String, String> {
-    if self.topic_exists(&topic_id) {
+  /// - FRAMED (optional) Store records length-prefixed so their content
+  ///   may contain line breaks.
+  /// - JSON (optional) Store records as JSON objects whose fields FIND
+  ///   WHERE can match on.
+  /// - SEGMENTED (optional) Store records in a directory of segment files
+  ///   that roll over at the configured size.
+  fn create(&self, args: &str) -> Result<String, String> {
+    let tokens: Vec<&str> = args.split_whitespace().collect();
+    let topic_id = match tokens.first() {
+      Some(topic_id) => *topic_id,
+      None => return Err("Create requires an id".to_string()),
+    };
+    let mut encoding = Encoding::Line;
+    let mut content = Content::Text;
+    let mut segmented = false;
+    for option in &tokens[1..] {
+      match option.to_uppercase().as_str() {
+        "FRAMED" => encoding = Encoding::Framed,
+        "JSON" => content = Content::Json,
+        "SEGMENTED" => segmented = true,
+        _ => return Err(format!("Unknown topic option {}", option)),
+      }
+    }
+    if self.storage(topic_id).is_some() {
       let message = format!("The topic {} already exists.", topic_id);
       return Err(message);
     }
-    match File::create(self.topic_path(topic_id)) {
+    let storage = if segmented {
+      Storage::segmented(&self.topic_base(topic_id))
+    } else {
+      Storage::single(&self.topic_base(topic_id))
+    };
+    let format = Format {
+      content,
+      ..Format::current(encoding)
+    };
+    match storage.create(&format.header()) {
       Ok(_) => {
         let message = format!("Topic {} created.", topic_id);
         Ok(message)
@@ -256,11 +2309,14 @@ impl ContextController for TopicController {
   }
 
   fn drop_item(&self, topic_id: &str) -> Result<String, String> {
-    if !self.topic_exists(&topic_id) {
-      let message = format!("The topic {} does not exist.", topic_id);
-      return Err(message);
-    }
-    match fs::remove_file(self.topic_path(topic_id)) {
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("The topic {} does not exist.", topic_id);
+        return Err(message);
+      }
+    };
+    match storage.remove() {
       Ok(_) => {
         let message = format!("Topic {} dropped.", topic_id);
         Ok(message)
@@ -273,42 +2329,548 @@ impl ContextController for TopicController {
   }
 
   fn list(&self) -> Vec<(String, String)> {
-    let mut items: Vec<(String, String)> = Vec::new();
-    let current_dir = format!("{}{}", self.db_home, self.relative_path);
-    let files = fs::read_dir(current_dir).unwrap();
-    for file in files {
-      let path = file.unwrap().path();
-      let topic_name = path.file_stem().unwrap().to_str().unwrap();
-      let topic_type = match path.extension() {
-        Some(value) => value.to_str().unwrap(),
-        _ => "",
-      };
-      if topic_type == "tpc" {
-        items.push(("".to_string(), topic_name.to_string()));
-      }
-    }
-    items
+    let topic_ids = self.topic_ids().unwrap();
+    topic_ids
+      .into_iter()
+      .map(|topic_id| ("".to_string(), topic_id))
+      .collect()
   }
 
   fn open(&self, topic_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    if !self.topic_exists(&topic_id) {
-      let message = format!("{} does not exist at {}.", topic_id, self.relative_path);
-      return DBResponse::Error(message);
-    }
-    let topic_path = self.topic_path(topic_id);
-    let topic = Topic::new(topic_id, &topic_path);
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("{} does not exist at {}.", topic_id, self.relative_path);
+        return DBResponse::Error(message);
+      }
+    };
+    let topic_path = storage.path().display().to_string();
+    let topic = match Topic::new(topic_id, storage, &self.config, None) {
+      Ok(topic) => topic,
+      Err(message) => return DBResponse::Error(message),
+    };
     debug!("topic opend for path {}", topic_path);
     let context_label = format!("{}[{}]", self.relative_path, topic_id);
     DBResponse::OpenContext((Box::new(topic), context_label))
   }
 
   fn compact(&self, topic_id: &str) -> DBResponse<(Box<dyn ContextProcess>, String)> {
-    if !self.topic_exists(&topic_id) {
-      let message = format!("{} does not exist.", topic_id);
-      return DBResponse::Invalid(message);
+    let storage = match self.storage(topic_id) {
+      Some(storage) => storage,
+      None => {
+        let message = format!("{} does not exist.", topic_id);
+        return DBResponse::Invalid(message);
+      }
+    };
+    let mut topic = match Topic::new(topic_id, storage.clone(), &self.config, None) {
+      Ok(topic) => topic,
+      Err(message) => return DBResponse::Error(message),
+    };
+    match topic.compact() {
+      DBResponse::ROk(message) => match remove_expired_backups(&storage, &self.config) {
+        0 => DBResponse::ROk(message),
+        removed => DBResponse::ROk(format!("{} {} old backup(s) removed.", message, removed)),
+      },
+      response => response,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::records::LEGACY_VERSION;
+
+  /// A database directory of the test's own, removed when dropped.
+  struct TestHome(PathBuf);
+
+  impl TestHome {
+    fn new(name: &str) -> TestHome {
+      let path = std::env::temp_dir().join(format!("listdb-{}-{}", name, std::process::id()));
+      let _ = fs::remove_dir_all(&path);
+      fs::create_dir_all(&path).expect("test directory can be created");
+      TestHome(path)
+    }
+
+    fn controller(&self, config: &EngineConfig) -> TopicController {
+      TopicController::new(&format!("{}/", self.0.display()), "", config)
+    }
+  }
+
+  impl Drop for TestHome {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn open(controller: &TopicController, topic_id: &str) -> Topic {
+    let storage = controller.storage(topic_id).expect("topic exists");
+    Topic::new(topic_id, storage, &controller.config, None).expect("topic opens")
+  }
+
+  fn add(topic: &mut Topic, content: &str) -> String {
+    match topic.process(&format!("ADD {}", content)) {
+      DBResponse::Created(id) => id,
+      _ => panic!("ADD {} failed", content),
+    }
+  }
+
+  fn run(topic: &mut Topic, request: &str) {
+    match topic.process(request) {
+      DBResponse::ROk(_) | DBResponse::Created(_) => (),
+      DBResponse::Invalid(message) | DBResponse::Error(message) => {
+        panic!("{} failed: {}", request, message)
+      }
+      _ => panic!("{} failed", request),
+    }
+  }
+
+  fn contents(topic: &Topic) -> Vec<&str> {
+    topic
+      .ordered_entries()
+      .into_iter()
+      .map(|entry| entry.content())
+      .collect()
+  }
+
+  #[test]
+  fn snapshot_written_by_compaction_is_used_on_reopen() {
+    let home = TestHome::new("compact-snapshot");
+    let config = EngineConfig {
+      snapshot_interval: Some(1),
+      ..EngineConfig::default()
+    };
+    let controller = home.controller(&config);
+    controller.create("t").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    add(&mut topic, "one");
+    add(&mut topic, "two");
+    let three = add(&mut topic, "three");
+    run(&mut topic, &format!("DELETE {}", three));
+    match topic.compact() {
+      DBResponse::ROk(_) => (),
+      _ => panic!("COMPACT failed"),
+    }
+    drop(topic);
+
+    let topic = open(&controller, "t");
+    let snapshot = topic.read_snapshot().expect("snapshot is written");
+    assert_eq!(snapshot.last_seq, 4);
+    assert_eq!(snapshot.check_seq, Some(2));
+    let files = topic.log_files().expect("files are listed");
+    assert!(topic.check_snapshot(&files, &snapshot).is_ok());
+    assert_eq!(contents(&topic), vec!["one", "two"]);
+    assert_eq!(topic.last_seq, 4);
+  }
+
+  #[test]
+  fn failed_append_leaves_counts_unchanged() {
+    let home = TestHome::new("failed-append");
+    let controller = home.controller(&EngineConfig::default());
+    controller.create("t").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    add(&mut topic, "one");
+    topic.release_writer().expect("writer is released");
+    let active = topic.active.clone();
+    topic.active = home.0.join("missing.tpc");
+    assert!(matches!(topic.process("ADD two"), DBResponse::Error(_)));
+    assert_eq!((topic.last_seq, topic.total_records), (1, 1));
+
+    topic.active = active;
+    add(&mut topic, "two");
+    assert_eq!((topic.last_seq, topic.total_records), (2, 2));
+    drop(topic);
+    let topic = open(&controller, "t");
+    assert_eq!(contents(&topic), vec!["one", "two"]);
+    assert_eq!(topic.last_seq, 2);
+  }
+
+  fn snapshot_config(interval: usize) -> EngineConfig {
+    EngineConfig {
+      snapshot_interval: Some(interval),
+      ..EngineConfig::default()
+    }
+  }
+
+  #[test]
+  fn reopen_resumes_from_snapshot() {
+    let home = TestHome::new("snapshot-resume");
+    let controller = home.controller(&snapshot_config(3));
+    controller.create("t").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    let one = add(&mut topic, "one");
+    add(&mut topic, "two");
+    add(&mut topic, "three");
+    run(&mut topic, &format!("UPDATE {} uno", one));
+    drop(topic);
+
+    let topic = open(&controller, "t");
+    let snapshot = topic.read_snapshot().expect("snapshot is written");
+    assert_eq!((snapshot.last_seq, snapshot.check_seq), (3, Some(3)));
+    assert_eq!(snapshot.records.len(), 3);
+    assert_eq!(topic.unsnapshotted, 1);
+    assert_eq!(contents(&topic), vec!["uno", "two", "three"]);
+    assert_eq!((topic.last_seq, topic.total_records), (4, 4));
+  }
+
+  #[test]
+  fn stale_snapshot_falls_back_to_full_replay() {
+    let home = TestHome::new("snapshot-stale");
+    let controller = home.controller(&snapshot_config(3));
+    controller.create("t").expect("topic is created");
+    let path = controller
+      .storage("t")
+      .expect("topic exists")
+      .path()
+      .to_path_buf();
+    let mut topic = open(&controller, "t");
+    add(&mut topic, "one");
+    add(&mut topic, "two");
+    let earlier = fs::read(&path).expect("topic file is readable");
+    add(&mut topic, "three");
+    drop(topic);
+    assert!(controller.storage("t").unwrap().snapshot_path().exists());
+
+    fs::write(&path, earlier).expect("topic file is writable");
+    let topic = open(&controller, "t");
+    let snapshot = topic.read_snapshot().expect("snapshot is still there");
+    let files = topic.log_files().expect("files are listed");
+    assert!(topic.check_snapshot(&files, &snapshot).is_err());
+    assert_eq!(contents(&topic), vec!["one", "two"]);
+    assert_eq!(topic.last_seq, 2);
+
+    fs::write(controller.storage("t").unwrap().snapshot_path(), "garbage")
+      .expect("snapshot is writable");
+    let topic = open(&controller, "t");
+    assert!(topic.read_snapshot().is_none());
+    assert_eq!(contents(&topic), vec!["one", "two"]);
+  }
+
+  #[test]
+  fn compaction_drops_dead_records_and_keeps_configured_backups() {
+    let home = TestHome::new("compact-retention");
+    let config = EngineConfig {
+      backup_keep_count: Some(2),
+      ..EngineConfig::default()
+    };
+    let controller = home.controller(&config);
+    controller.create("t").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    let one = add(&mut topic, "one");
+    add(&mut topic, "two");
+    run(&mut topic, &format!("UPDATE {} uno", one));
+    drop(topic);
+    for _ in 0..3 {
+      let mut topic = open(&controller, "t");
+      let gone = add(&mut topic, "gone");
+      run(&mut topic, &format!("DELETE {}", gone));
+      drop(topic);
+      match controller.compact("t") {
+        DBResponse::ROk(_) => (),
+        _ => panic!("COMPACT failed"),
+      }
+    }
+
+    let storage = controller.storage("t").expect("topic exists");
+    assert_eq!(storage.backups().len(), 2);
+    let topic = open(&controller, "t");
+    assert_eq!(contents(&topic), vec!["uno", "two"]);
+    assert_eq!((topic.last_seq, topic.total_records), (9, 3));
+  }
+
+  fn file_names(storage: &Storage) -> Vec<String> {
+    storage
+      .files()
+      .expect("files are listed")
+      .iter()
+      .map(|file| file.file_name().unwrap().to_string_lossy().to_string())
+      .collect()
+  }
+
+  #[test]
+  fn segments_roll_and_compact_one_at_a_time() {
+    let home = TestHome::new("segments");
+    let header = Format::current(Encoding::Line).header();
+    let config = EngineConfig {
+      segment_size: header.len() as u64 + 1,
+      ..EngineConfig::default()
+    };
+    let controller = home.controller(&config);
+    controller.create("t SEGMENTED").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    add(&mut topic, "one");
+    let two = add(&mut topic, "two");
+    add(&mut topic, "three");
+    run(&mut topic, &format!("DELETE {}", two));
+    let storage = controller.storage("t").expect("topic exists");
+    assert!(storage.is_segmented());
+    assert_eq!(file_names(&storage).len(), 4);
+
+    match topic.compact() {
+      DBResponse::ROk(message) => {
+        assert_eq!(message, "Topic compacted. 2 of 4 segments rewritten.")
+      }
+      _ => panic!("COMPACT failed"),
+    }
+    assert_eq!(
+      file_names(&storage),
+      vec!["00000001.seg", "00000003.seg", "00000004.seg"]
+    );
+    drop(topic);
+
+    let mut topic = open(&controller, "t");
+    assert_eq!(contents(&topic), vec!["one", "three"]);
+    assert_eq!(topic.last_seq, 4);
+    add(&mut topic, "four");
+    assert_eq!(topic.last_seq, 5);
+    assert_eq!(file_names(&storage).len(), 4);
+  }
+
+  fn ok(response: DBResponse<(Box<dyn ContextProcess>, String)>) -> String {
+    match response {
+      DBResponse::ROk(message) => message,
+      DBResponse::Invalid(message) | DBResponse::Error(message) => panic!("{}", message),
+      _ => panic!("unexpected response"),
+    }
+  }
+
+  #[test]
+  fn restore_single_file_topic_and_undo_it() {
+    let home = TestHome::new("restore-single");
+    let controller = home.controller(&EngineConfig::default());
+    controller.create("t").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    add(&mut topic, "one");
+    let two = add(&mut topic, "two");
+    run(&mut topic, &format!("DELETE {}", two));
+    ok(topic.compact());
+    assert_eq!(topic.total_records, 1);
+    drop(topic);
+
+    ok(controller.restore("t"));
+    let topic = open(&controller, "t");
+    assert_eq!(contents(&topic), vec!["one"]);
+    assert_eq!((topic.last_seq, topic.total_records), (3, 3));
+    drop(topic);
+
+    ok(controller.restore("t"));
+    let topic = open(&controller, "t");
+    assert_eq!((topic.last_seq, topic.total_records), (3, 1));
+  }
+
+  #[test]
+  fn restore_keeps_an_empty_topic_file() {
+    let home = TestHome::new("restore-empty");
+    let controller = home.controller(&EngineConfig::default());
+    let path = home.0.join(format!("e.{}", TOPIC_EXTENSION));
+    fs::write(&path, "").expect("topic file is written");
+    ok(controller.migrate("e"));
+    assert!(!fs::read(&path).unwrap().is_empty());
+
+    ok(controller.restore("e"));
+    assert!(fs::read(&path).expect("topic file is kept").is_empty());
+    let topic = open(&controller, "e");
+    assert_eq!(topic.total_records, 0);
+  }
+
+  #[test]
+  fn restore_segmented_topic_brings_back_removed_segments() {
+    let home = TestHome::new("restore-segmented");
+    let header = Format::current(Encoding::Line).header();
+    let config = EngineConfig {
+      segment_size: header.len() as u64 + 1,
+      ..EngineConfig::default()
+    };
+    let controller = home.controller(&config);
+    controller.create("t SEGMENTED").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    let one = add(&mut topic, "one");
+    add(&mut topic, "two");
+    add(&mut topic, "three");
+    run(&mut topic, &format!("DELETE {}", one));
+    ok(topic.compact());
+    drop(topic);
+    let storage = controller.storage("t").expect("topic exists");
+    assert_eq!(file_names(&storage).len(), 3);
+
+    let message = ok(controller.restore("t"));
+    assert_eq!(file_names(&storage).len(), 4);
+    let stamp = message.rsplit(' ').next().unwrap().trim_end_matches('.');
+    let segment = storage.path().join("00000001.seg");
+    assert!(storage::missing_marker_path(&segment, stamp).exists());
+    let topic = open(&controller, "t");
+    assert_eq!(contents(&topic), vec!["two", "three"]);
+    assert_eq!(topic.total_records, 4);
+    drop(topic);
+
+    ok(controller.restore("t"));
+    assert_eq!(
+      file_names(&storage),
+      vec!["00000002.seg", "00000003.seg", "00000004.seg"]
+    );
+    let topic = open(&controller, "t");
+    assert_eq!(contents(&topic), vec!["two", "three"]);
+    assert_eq!((topic.last_seq, topic.total_records), (4, 2));
+  }
+
+  fn record(id: &str, action: &str, content: &str, seq: u64) -> Record {
+    Record {
+      id: id.to_string(),
+      action: action.to_string(),
+      content: content.to_string(),
+      seq,
+      timestamp: Some(Utc::now()),
+    }
+  }
+
+  #[test]
+  fn repair_drops_orphan_updates() {
+    let home = TestHome::new("repair");
+    let controller = home.controller(&EngineConfig::default());
+    controller.create("t").expect("topic is created");
+    let storage = controller.storage("t").expect("topic exists");
+    let format = Format::current(Encoding::Line);
+    let mut file = format.header();
+    for record in [
+      record("a", ACTION_ADD, "one", 1),
+      record("b", ACTION_UPDATE, "orphan", 2),
+      record("a", ACTION_UPDATE, "uno", 3),
+      record("c", ACTION_ADD, "three", 4),
+    ] {
+      file.extend_from_slice(&format.encode(&record));
+    }
+    fs::write(storage.path(), &file).expect("topic file is written");
+    match controller.verify("t") {
+      DBResponse::Data(items) => {
+        assert_eq!(items.len(), 1);
+        assert!(items[0].1.starts_with("update of b which does not exist"));
+      }
+      _ => panic!("VERIFY found no problems"),
     }
-    let topic_path = self.topic_path(topic_id);
-    let mut topic = Topic::new(topic_id, &topic_path);
-    topic.compact()
+
+    ok(controller.repair("t"));
+    ok(controller.verify("t"));
+    let backups = storage.backups();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(fs::read(&backups[0].1[0]).unwrap(), file);
+    let topic = open(&controller, "t");
+    assert_eq!(contents(&topic), vec!["uno", "three"]);
+    assert_eq!((topic.last_seq, topic.total_records), (4, 3));
+  }
+
+  #[test]
+  fn migration_from_legacy_format_keeps_order_and_ids() {
+    let home = TestHome::new("migrate-legacy");
+    let controller = home.controller(&EngineConfig::default());
+    let legacy = Format {
+      version: LEGACY_VERSION,
+      encoding: Encoding::Line,
+      content: Content::Text,
+      prior_seq: 0,
+    };
+    let [a, b, c] = [(); 3].map(|_| Uuid::new_v4().to_string());
+    let mut file = legacy.header();
+    for (id, action, content) in [
+      (&a, ACTION_ADD, "one"),
+      (&b, ACTION_ADD, "two"),
+      (&a, ACTION_UPDATE, "uno"),
+      (&c, ACTION_ADD, "three"),
+      (&b, ACTION_DELETE, "-"),
+    ] {
+      let mut record = record(id, action, content, 0);
+      record.timestamp = None;
+      file.extend_from_slice(&legacy.encode(&record));
+    }
+    let path = home.0.join(format!("t.{}", TOPIC_EXTENSION));
+    fs::write(&path, &file).expect("topic file is written");
+
+    ok(controller.migrate("t"));
+    let reader = LogReader::open(&path, 0).expect("migrated file is readable");
+    assert!(reader.format.is_current());
+    let backups = controller.storage("t").expect("topic exists").backups();
+    assert_eq!(fs::read(&backups[0].1[0]).unwrap(), file);
+
+    let mut topic = open(&controller, "t");
+    let entries = topic.ordered_entries();
+    let ids: Vec<&str> = entries
+      .iter()
+      .map(|entry| entry.added.id.as_str())
+      .collect();
+    assert_eq!(ids, vec![a.as_str(), c.as_str()]);
+    let seqs: Vec<u64> = entries.iter().map(|entry| entry.latest.seq).collect();
+    assert_eq!(seqs, vec![3, 4]);
+    assert!(entries.iter().all(|entry| entry.latest.timestamp.is_none()));
+    assert_eq!(contents(&topic), vec!["uno", "three"]);
+    assert_eq!((topic.last_seq, topic.total_records), (5, 5));
+    add(&mut topic, "four");
+    assert_eq!(topic.last_seq, 6);
+  }
+
+  #[test]
+  fn resolve_id_accepts_unique_prefixes_only() {
+    let home = TestHome::new("resolve-id");
+    let controller = home.controller(&EngineConfig::default());
+    controller.create("t").expect("topic is created");
+    let mut topic = open(&controller, "t");
+    for key in ["abcd1", "abcd2", "wxyz9"] {
+      run(&mut topic, &format!("PUT {} {}", key, key));
+    }
+    assert_eq!(topic.resolve_id("abcd1"), Ok("abcd1".to_string()));
+    assert_eq!(topic.resolve_id("wxyz"), Ok("wxyz9".to_string()));
+    assert_eq!(
+      topic.resolve_id("abcd"),
+      Err("Record abcd is ambiguous. (matches abcd1, abcd2)".to_string())
+    );
+    assert_eq!(
+      topic.resolve_id("wxy"),
+      Err("Record wxy does not exist.".to_string())
+    );
+    assert_eq!(
+      topic.resolve_id("abcd3"),
+      Err("Record abcd3 does not exist.".to_string())
+    );
+  }
+
+  fn local(date: &str) -> DateTime<Utc> {
+    let naive = NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S").unwrap();
+    Local
+      .from_local_datetime(&naive)
+      .earliest()
+      .unwrap()
+      .with_timezone(&Utc)
+  }
+
+  #[test]
+  fn as_of_parses_sequence_numbers_and_times() {
+    assert!(matches!(AsOf::parse(" 42 "), Ok(AsOf::Sequence(42))));
+    let utc = Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 0).unwrap();
+    assert!(matches!(AsOf::parse("2024-03-01T12:30:00Z"), Ok(AsOf::Time(time)) if time == utc));
+    assert!(
+      matches!(AsOf::parse("2024-03-01T14:30:00+02:00"), Ok(AsOf::Time(time)) if time == utc)
+    );
+    let afternoon = local("2024-03-01 12:30:00");
+    assert!(
+      matches!(AsOf::parse("2024-03-01 12:30:00"), Ok(AsOf::Time(time)) if time == afternoon)
+    );
+    assert!(
+      matches!(AsOf::parse("2024-03-01T12:30:00"), Ok(AsOf::Time(time)) if time == afternoon)
+    );
+    let midnight = local("2024-03-01 00:00:00");
+    assert!(matches!(AsOf::parse("2024-03-01"), Ok(AsOf::Time(time)) if time == midnight));
+    assert!(AsOf::parse("yesterday").is_err());
+    assert!(AsOf::parse("-1").is_err());
+  }
+
+  #[test]
+  fn as_of_time_includes_records_without_a_time() {
+    let as_of = AsOf::Time(Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap());
+    let mut migrated = record("a", ACTION_ADD, "one", 1);
+    migrated.timestamp = None;
+    let mut later = record("b", ACTION_ADD, "two", 2);
+    later.timestamp = Some(Utc.with_ymd_and_hms(2024, 3, 2, 0, 0, 0).unwrap());
+    assert!(as_of.includes(&migrated));
+    assert!(!as_of.includes(&later));
+    assert!(AsOf::Sequence(1).includes(&migrated));
+    assert!(!AsOf::Sequence(1).includes(&later));
   }
 }
@@ -0,0 +1,220 @@
+//! Checking a topic's log for problems and rewriting it without them.
+//!
+//! Besides records that cannot be read, a log can hold records that read
+//! fine but make no sense in the order they were written: an unknown action
+//! code, an id that is not a valid key, an update, move or delete of an id
+//! that was never added (or was already deleted), a second add of a live
+//! id, an invalid list position, content a `JSON` topic cannot hold, or a
+//! sequence number that does not follow the one before it.
+
+use crate::positions::{self, Position};
+use crate::records::{check_key, Encoding, Format, LogReader, Record};
+use crate::records::{ACTION_ADD, ACTION_DELETE, ACTION_INSERT, ACTION_MOVE, ACTION_UPDATE};
+use crate::storage::{backup_path, temporary_path, Storage, REPAIR_TEMPORARY};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// A problem found in a topic's log.
+pub struct Anomaly {
+  /// File the problem was found in.
+  pub file: PathBuf,
+  /// Line (or frame, in a `FRAMED` file) the problem was found at.
+  pub location: String,
+  pub offset: usize,
+  pub problem: String,
+}
+
+/// Outcome of checking or repairing a topic.
+pub struct Report {
+  pub anomalies: Vec<Anomaly>,
+  /// Number of readable records in the log.
+  pub records: usize,
+  /// Number of readable records left out of the repaired log.
+  pub dropped: usize,
+  /// Number of files rewritten by a repair.
+  pub rewritten: usize,
+}
+
+/// Checks each record against the records before it.
+struct Checker {
+  live: HashSet<String>,
+  last_seq: Option<u64>,
+}
+
+impl Checker {
+  /// Returns the problem with a record, or applies it to the live ids.
+  /// Sequence numbers are checked against every record read, whether or
+  /// not it passed the other checks.
+  fn check(&mut self, record: &Record, format: &Format) -> Option<String> {
+    if format.has_stamps() {
+      let last_seq = self.last_seq;
+      self.last_seq = Some(last_seq.map_or(record.seq, |last_seq| last_seq.max(record.seq)));
+      if let Some(last_seq) = last_seq.filter(|last_seq| record.seq <= *last_seq) {
+        return Some(format!(
+          "sequence number {} does not follow {}",
+          record.seq, last_seq
+        ));
+      }
+    }
+    let actions = [
+      ACTION_ADD,
+      ACTION_UPDATE,
+      ACTION_DELETE,
+      ACTION_INSERT,
+      ACTION_MOVE,
+    ];
+    if !actions.contains(&record.action.as_str()) {
+      return Some(format!("unknown action code \"{}\"", record.action));
+    }
+    if check_key(&record.id).is_err() {
+      return Some(format!("malformed id \"{}\"", record.id));
+    }
+    let content = match record.action.as_str() {
+      ACTION_INSERT => positions::split_insert(&record.content).map(|(_, content)| Some(content)),
+      ACTION_MOVE => Position::parse(&record.content).map(|_| None),
+      ACTION_DELETE => Ok(None),
+      _ => Ok(Some(record.content.as_str())),
+    };
+    let content = match content {
+      Ok(content) => content,
+      Err(problem) => return Some(problem),
+    };
+    if let Some(problem) = content.and_then(|content| format.reject_content(content)) {
+      return Some(format!(
+        "rejected content ({})",
+        problem.trim_end_matches('.')
+      ));
+    }
+    let live = self.live.contains(&record.id);
+    match record.action.as_str() {
+      ACTION_ADD | ACTION_INSERT if live => {
+        return Some(format!("add of {} which already exists", record.id))
+      }
+      ACTION_UPDATE if !live => {
+        return Some(format!("update of {} which does not exist", record.id))
+      }
+      ACTION_MOVE if !live => return Some(format!("move of {} which does not exist", record.id)),
+      ACTION_DELETE if !live => {
+        return Some(format!("delete of {} which does not exist", record.id))
+      }
+      ACTION_ADD | ACTION_INSERT => {
+        self.live.insert(record.id.clone());
+      }
+      ACTION_DELETE => {
+        self.live.remove(&record.id);
+      }
+      _ => (),
+    }
+    None
+  }
+}
+
+/// Checks every record of a topic and reports the problems found.
+pub fn verify(storage: &Storage) -> Result<Report, String> {
+  check_topic(storage, None)
+}
+
+/// Rewrites each file of a topic that has problems with only the records
+/// that passed the checks, keeping the original as a backup taken at
+/// `stamp`. A segment left with no records is removed unless it is the last
+/// one.
+pub fn repair(storage: &Storage, stamp: &str) -> Result<Report, String> {
+  check_topic(storage, Some(stamp))
+}
+
+fn check_topic(storage: &Storage, repair_stamp: Option<&str>) -> Result<Report, String> {
+  let files = storage.files().map_err(|error| error.to_string())?;
+  let mut report = Report {
+    anomalies: Vec::new(),
+    records: 0,
+    dropped: 0,
+    rewritten: 0,
+  };
+  let mut checker = Checker {
+    live: HashSet::new(),
+    last_seq: None,
+  };
+  let file_count = files.len();
+  for (index, file) in files.iter().enumerate() {
+    let mut reader =
+      LogReader::open(file, 0).map_err(|message| format!("{}: {}", file.display(), message))?;
+    let unit = match reader.format.encoding {
+      Encoding::Line => "line",
+      Encoding::Framed => "frame",
+    };
+    let anomaly = |offset: usize, entry: usize, problem: String| Anomaly {
+      file: file.clone(),
+      location: format!("{} {}", unit, entry),
+      offset,
+      problem,
+    };
+    let repaired = temporary_path(file, REPAIR_TEMPORARY);
+    let mut writer = match repair_stamp {
+      Some(_) => {
+        let file = File::create(&repaired).map_err(|error| error.to_string())?;
+        let mut writer = BufWriter::new(file);
+        writer
+          .write_all(&reader.format.header())
+          .map_err(|error| error.to_string())?;
+        Some(writer)
+      }
+      None => None,
+    };
+    let found = report.anomalies.len();
+    let mut kept = 0;
+    while let Some(record) = reader.next_record()? {
+      report.records += 1;
+      for (offset, entry, reason) in reader.corrupt.drain(..) {
+        report.anomalies.push(anomaly(offset, entry, reason));
+      }
+      match checker.check(&record, &reader.format) {
+        Some(problem) => {
+          let offset = reader.last_offset.unwrap_or(0);
+          report
+            .anomalies
+            .push(anomaly(offset, reader.entry, problem));
+          report.dropped += 1;
+        }
+        None => {
+          kept += 1;
+          if let Some(writer) = writer.as_mut() {
+            writer
+              .write_all(&reader.format.encode(&record))
+              .map_err(|error| error.to_string())?;
+          }
+        }
+      }
+    }
+    for (offset, entry, reason) in reader.corrupt.drain(..) {
+      report.anomalies.push(anomaly(offset, entry, reason));
+    }
+    if let Some((offset, entry)) = reader.incomplete {
+      let length = reader.file_len() - offset;
+      let problem = format!("incomplete record at end of file ({} bytes)", length);
+      report.anomalies.push(anomaly(offset, entry, problem));
+    }
+    drop(reader);
+    let (mut writer, stamp) = match (writer, repair_stamp) {
+      (Some(writer), Some(stamp)) => (writer, stamp),
+      _ => continue,
+    };
+    writer.flush().map_err(|error| error.to_string())?;
+    drop(writer);
+    if report.anomalies.len() == found {
+      fs::remove_file(&repaired).map_err(|error| error.to_string())?;
+      continue;
+    }
+    let backup = backup_path(file, stamp);
+    if kept == 0 && index + 1 < file_count {
+      fs::rename(file, backup).map_err(|error| error.to_string())?;
+      fs::remove_file(&repaired).map_err(|error| error.to_string())?;
+    } else {
+      fs::hard_link(file, backup).map_err(|error| error.to_string())?;
+      fs::rename(&repaired, file).map_err(|error| error.to_string())?;
+    }
+    report.rewritten += 1;
+  }
+  Ok(report)
+}